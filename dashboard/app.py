@@ -21,28 +21,45 @@ import uuid
 st.header("💸 User PnL Tracker")
 
 user_query = st.text_input("Enter user ID to check PnL", key="pnl_user")
+pnl_symbol = st.text_input("Symbol", value="AAPL", key="pnl_symbol")
 
 if user_query:
-    pnl_key = f"user_pnl:{user_query}"
+    pnl_key = f"user_pnl:{user_query}:{pnl_symbol.upper()}"
     pnl_data = r.hgetall(pnl_key)
     
     if pnl_data and "pnl" in pnl_data:
         pnl_val = float(pnl_data["pnl"])
         pnl_color = "🟢" if pnl_val > 0 else ("🟡" if pnl_val == 0 else "🔴")
-        st.metric(label=f"Realized PnL for {user_query}", value=f"${pnl_val:.2f}", delta=pnl_color)
+        st.metric(label=f"Realized PnL for {user_query} in {pnl_symbol.upper()}", value=f"${pnl_val:.2f}", delta=pnl_color)
     else:
         st.info("No PnL data available for this user yet.")
 
+st.header("📖 Top of Book")
+
+quote_symbol = st.text_input("Symbol", value="AAPL", key="quote_symbol")
+try:
+    channel = grpc.insecure_channel("localhost:50051")
+    stub = order_pb2_grpc.OrderMatchingStub(channel)
+    quote = stub.GetQuote(order_pb2.GetQuoteRequest(symbol=quote_symbol.upper()))
+    bid_col, ask_col, spread_col, last_col = st.columns(4)
+    bid_col.metric("Bid", f"{quote.bid_price or '-'} x {quote.bid_quantity}")
+    ask_col.metric("Ask", f"{quote.ask_price or '-'} x {quote.ask_quantity}")
+    spread_col.metric("Spread", quote.spread or "-")
+    last_col.metric("Last", f"{quote.last_trade_price or '-'} x {quote.last_trade_quantity}")
+except grpc.RpcError as e:
+    st.error(f"❌ gRPC error: {e.code()}")
+
 st.header("📝 Submit New Order")
 
 with st.form("order_form"):
     default_id = st.session_state.get("default_user_id", str(uuid.uuid4())[:8])
     user_id = st.text_input("User ID", value=default_id)
     st.session_state["default_user_id"] = default_id
+    symbol = st.text_input("Symbol", value="AAPL")
     side = st.selectbox("Side", ["buy", "sell"])
     order_type = st.selectbox("Order Type", ["limit", "market"])
     quantity = st.number_input("Quantity", min_value=1, step=1)
-    price = st.number_input("Price", min_value=1.0, step=0.5) if order_type == "limit" else 0
+    price = st.text_input("Price", value="150.00") if order_type == "limit" else ""
 
     submitted = st.form_submit_button("Submit Order")
 
@@ -53,11 +70,11 @@ with st.form("order_form"):
 
             request = order_pb2.OrderRequest(
                 user_id=user_id,
-                symbol="AAPL",
+                symbol=symbol.upper(),
                 side=side,
                 order_type=order_type,
                 quantity=int(quantity),
-                price=int(price)
+                price=price.strip()
             )
 
             response = stub.SubmitOrder(request)
@@ -72,6 +89,7 @@ trades = r.xrevrange("trades_stream", count=200)
 rows = []
 for trade_id, fields in reversed(trades):
     rows.append({
+        "Symbol": fields.get("symbol", ""),
         "Price": float(fields["price"]),
         "Qty": int(fields["quantity"]),
         "Buyer": fields["buyer"],
@@ -0,0 +1,551 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::{AllocationStep, Order};
+use crate::price_level::PriceLevel;
+
+/// Decides how an incoming order's quantity is shared among the resting
+/// orders at one price level.
+///
+/// The engine calls `allocate` once per allocation pass and applies the
+/// fills it returns. Implementations only see shown quantity; the engine
+/// takes care of iceberg replenishment, self-trade prevention and moving on
+/// to the next price. Custom algorithms implement this trait and are
+/// installed with `OrderBook::strategy`.
+pub trait AllocationStrategy: Send {
+    /// Returns fills for at most `quantity`, none larger than the resting
+    /// order's shown quantity. The engine clamps fills that break this.
+    fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation;
+}
+
+/// A price level as a strategy sees it. Quantity already given out by an
+/// outer strategy, such as `LmmAllocation`, is taken off the shown
+/// quantities without copying the level.
+pub struct LevelView<'a> {
+    level: &'a PriceLevel,
+    /// `(slot, quantity)` already allocated.
+    taken: Vec<(u64, u64)>,
+}
+
+impl<'a> LevelView<'a> {
+    pub fn new(level: &'a PriceLevel) -> Self {
+        Self { level, taken: vec![] }
+    }
+
+    /// `(slot, order, shown quantity)` in time priority, leaving out orders
+    /// with nothing left to show.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &'a Order, u64)> + '_ {
+        self.level.iter().filter_map(|(slot, order)| {
+            let taken: u64 = self.taken.iter().filter(|&&(s, _)| s == slot).map(|&(_, qty)| qty).sum();
+            let shown = order.shown_quantity.saturating_sub(taken);
+            (shown > 0).then_some((slot, order, shown))
+        })
+    }
+
+    /// Summed in `u128`: a level of very large orders can hold more than
+    /// `u64::MAX` in total.
+    pub fn visible_quantity(&self) -> u128 {
+        self.iter().map(|(_, _, shown)| shown as u128).sum()
+    }
+}
+
+/// Which orders receive the lots that proportional rounding leaves over.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LeftoverRule {
+    /// One lot at a time to the orders whose exact share lost the largest
+    /// fraction to rounding; equal remainders go in time priority.
+    #[default]
+    LargestRemainder,
+    /// Filled in time priority, like FIFO.
+    TimePriority,
+}
+
+/// How pro-rata mode splits an incoming order across one price level.
+///
+/// The steps run in order: top order, FIFO portion, proportional split,
+/// leftover. The default is plain largest-remainder pro-rata.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ProRataPolicy {
+    /// The oldest order at the level is filled first, up to
+    /// `top_order_max` if set.
+    pub top_order: bool,
+    pub top_order_max: Option<u64>,
+    /// Share of the incoming quantity, in percent (0-100), allocated in
+    /// time priority before the proportional split.
+    pub fifo_percent: u8,
+    /// Proportional allocations below this quantity are dropped and go to
+    /// the leftover step instead.
+    pub min_allocation: u64,
+    pub leftover: LeftoverRule,
+}
+
+/// One step of the allocation at a level, kept for audit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllocationDecision {
+    pub slot: u64,
+    pub step: AllocationStep,
+    pub quantity: u64,
+}
+
+/// What an incoming order gets at one price level.
+#[derive(Debug, Default, PartialEq)]
+pub struct LevelAllocation {
+    /// `(slot, quantity)` per resting order, in the order trades are printed.
+    pub fills: Vec<(u64, u64)>,
+    pub decisions: Vec<AllocationDecision>,
+}
+
+/// Fills `ranked` orders one after another with what is left of `quantity`.
+fn fill_in_order(ranked: impl Iterator<Item = (u64, u64)>, quantity: u64, step: AllocationStep) -> LevelAllocation {
+    let mut allocation = LevelAllocation::default();
+    let mut remaining = quantity;
+
+    for (slot, shown) in ranked {
+        if remaining == 0 {
+            break;
+        }
+        let trade_qty = remaining.min(shown);
+        if trade_qty > 0 {
+            allocation.fills.push((slot, trade_qty));
+            allocation.decisions.push(AllocationDecision { slot, step, quantity: trade_qty });
+            remaining -= trade_qty;
+        }
+    }
+
+    allocation
+}
+
+/// Strict time priority.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FifoAllocation;
+
+impl AllocationStrategy for FifoAllocation {
+    fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation {
+        let ranked = level.iter().map(|(slot, _, shown)| (slot, shown));
+        fill_in_order(ranked, quantity, AllocationStep::Fifo)
+    }
+}
+
+/// Largest shown quantity first; equal sizes go in time priority.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizePriorityAllocation;
+
+impl AllocationStrategy for SizePriorityAllocation {
+    fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation {
+        let mut ranked: Vec<(u64, u64)> = level.iter().map(|(slot, _, shown)| (slot, shown)).collect();
+        ranked.sort_by_key(|&(slot, shown)| (std::cmp::Reverse(shown), slot));
+        fill_in_order(ranked.into_iter(), quantity, AllocationStep::SizePriority)
+    }
+}
+
+/// Pro-rata according to `policy`. Iceberg orders take part with their
+/// shown slice only; the hidden reserve earns no allocation until it is
+/// displayed.
+///
+/// Proportional shares are computed exactly in integers, rounded down to
+/// whole lots of `lot_size`, so every node allocates identically. The
+/// result always sums to `quantity` or the level's shown quantity, whichever
+/// is smaller, and never exceeds any order's shown quantity.
+#[derive(Debug, Clone, Copy)]
+pub struct ProRataAllocation {
+    pub policy: ProRataPolicy,
+    pub lot_size: u64,
+}
+
+impl Default for ProRataAllocation {
+    fn default() -> Self {
+        Self { policy: ProRataPolicy::default(), lot_size: 1 }
+    }
+}
+
+impl AllocationStrategy for ProRataAllocation {
+    fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation {
+        pro_rata_fills(level, quantity, &self.policy, self.lot_size)
+    }
+}
+
+/// Lead market makers registered for an instrument, ahead of its matching
+/// mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LmmPolicy {
+    pub user_ids: Vec<String>,
+    /// Share of each incoming order reserved for the makers at the price
+    /// where they are quoting.
+    pub percent: u8,
+}
+
+/// Lead market makers first: resting orders from `makers` share `percent`
+/// of the incoming quantity in time priority, and whatever is left goes to
+/// the `residual` strategy across the whole level.
+pub struct LmmAllocation {
+    pub makers: HashSet<String>,
+    pub percent: u8,
+    pub residual: Box<dyn AllocationStrategy>,
+}
+
+impl AllocationStrategy for LmmAllocation {
+    fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation {
+        let quota = (quantity as u128 * self.percent.min(100) as u128 / 100) as u64;
+        let ranked = level.iter()
+            .filter(|(_, o, _)| self.makers.contains(&o.user_id))
+            .map(|(slot, _, shown)| (slot, shown));
+        let lmm = fill_in_order(ranked, quota, AllocationStep::LeadMarketMaker);
+        if lmm.fills.is_empty() {
+            return self.residual.allocate(level, quantity);
+        }
+
+        // The residual strategy sees the level minus what the makers took
+        let given: u64 = lmm.fills.iter().map(|&(_, qty)| qty).sum();
+        let rest = LevelView {
+            level: level.level,
+            taken: level.taken.iter().chain(&lmm.fills).copied().collect(),
+        };
+        let residual = self.residual.allocate(&rest, quantity - given);
+
+        let mut fills: BTreeMap<u64, u64> = BTreeMap::new();
+        for &(slot, qty) in lmm.fills.iter().chain(&residual.fills) {
+            *fills.entry(slot).or_default() += qty;
+        }
+        LevelAllocation {
+            fills: fills.into_iter().collect(),
+            decisions: lmm.decisions.into_iter().chain(residual.decisions).collect(),
+        }
+    }
+}
+
+fn pro_rata_fills(level: &LevelView, quantity: u64, policy: &ProRataPolicy, lot_size: u64) -> LevelAllocation {
+    let mut allocator = Allocator {
+        slots: level.iter().map(|(slot, _, _)| slot).collect(),
+        capacity: level.iter().map(|(_, _, shown)| shown).collect(),
+        allocated: vec![0; level.iter().count()],
+        remaining: (quantity as u128).min(level.visible_quantity()) as u64,
+        decisions: vec![],
+    };
+    let n = allocator.slots.len();
+
+    if policy.top_order && n > 0 {
+        let cap = policy.top_order_max.unwrap_or(u64::MAX);
+        allocator.give(0, cap, AllocationStep::TopOrder);
+    }
+
+    let mut fifo_portion = (allocator.remaining as u128 * policy.fifo_percent as u128 / 100) as u64;
+    for i in 0..n {
+        if fifo_portion == 0 {
+            break;
+        }
+        fifo_portion -= allocator.give(i, fifo_portion, AllocationStep::Fifo);
+    }
+
+    // share = lots * available / total, kept as quotient and remainder
+    let lots = (allocator.remaining / lot_size) as u128;
+    let total_available: u128 = (0..n).map(|i| allocator.available(i) as u128).sum();
+    let mut remainders = vec![0u128; n];
+    if lots > 0 && total_available > 0 {
+        let shares: Vec<u64> = (0..n)
+            .map(|i| {
+                let weighted = lots * allocator.available(i) as u128;
+                remainders[i] = weighted % total_available;
+                (weighted / total_available) as u64 * lot_size
+            })
+            .collect();
+        for (i, share) in shares.into_iter().enumerate() {
+            if share == 0 {
+                continue;
+            }
+            if share < policy.min_allocation {
+                allocator.record(i, AllocationStep::BelowMinimum, share);
+                continue;
+            }
+            allocator.give(i, share, AllocationStep::ProRata);
+        }
+    }
+
+    match policy.leftover {
+        LeftoverRule::TimePriority => {
+            for i in 0..n {
+                allocator.give(i, u64::MAX, AllocationStep::Leftover);
+            }
+        }
+        LeftoverRule::LargestRemainder => {
+            let mut ranked: Vec<usize> = (0..n).collect();
+            ranked.sort_by_key(|&i| (std::cmp::Reverse(remainders[i]), i));
+
+            let mut leftover = vec![0; n];
+            while allocator.remaining > 0 {
+                let mut progressed = false;
+                for &i in &ranked {
+                    let lot = lot_size.min(allocator.remaining).min(allocator.available(i));
+                    if lot > 0 {
+                        allocator.allocated[i] += lot;
+                        allocator.remaining -= lot;
+                        leftover[i] += lot;
+                        progressed = true;
+                    }
+                }
+                if !progressed {
+                    break;
+                }
+            }
+            for (i, quantity) in leftover.into_iter().enumerate() {
+                if quantity > 0 {
+                    allocator.record(i, AllocationStep::Leftover, quantity);
+                }
+            }
+        }
+    }
+
+    LevelAllocation {
+        fills: allocator.slots.iter()
+            .zip(&allocator.allocated)
+            .filter(|(_, &qty)| qty > 0)
+            .map(|(&slot, &qty)| (slot, qty))
+            .collect(),
+        decisions: allocator.decisions,
+    }
+}
+
+/// Running state of one pro-rata allocation, indexed like the level's
+/// orders in time priority.
+struct Allocator {
+    slots: Vec<u64>,
+    /// Shown quantity of each order.
+    capacity: Vec<u64>,
+    allocated: Vec<u64>,
+    remaining: u64,
+    decisions: Vec<AllocationDecision>,
+}
+
+impl Allocator {
+    fn available(&self, i: usize) -> u64 {
+        self.capacity[i] - self.allocated[i]
+    }
+
+    /// Gives order `i` up to `max` more, bounded by what it and the incoming
+    /// order have left, and returns how much it got.
+    fn give(&mut self, i: usize, max: u64, step: AllocationStep) -> u64 {
+        let quantity = max.min(self.remaining).min(self.available(i));
+        if quantity > 0 {
+            self.allocated[i] += quantity;
+            self.remaining -= quantity;
+            self.record(i, step, quantity);
+        }
+        quantity
+    }
+
+    fn record(&mut self, i: usize, step: AllocationStep, quantity: u64) {
+        self.decisions.push(AllocationDecision { slot: self.slots[i], step, quantity });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::models::{Order, OrderType, Side};
+
+    fn level(sizes: &[u64]) -> PriceLevel {
+        let mut level = PriceLevel::default();
+        for (slot, &qty) in sizes.iter().enumerate() {
+            let order = Order::new(format!("u{}", slot), "AAPL".to_string(), Side::Sell, OrderType::Limit, Some(100), qty);
+            level.push_back(slot as u64, order);
+        }
+        level
+    }
+
+    fn steps(allocation: &LevelAllocation, step: AllocationStep) -> Vec<(u64, u64)> {
+        allocation.decisions.iter()
+            .filter(|d| d.step == step)
+            .map(|d| (d.slot, d.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_policy_is_plain_pro_rata() {
+        let allocation = ProRataAllocation::default().allocate(&LevelView::new(&level(&[10, 20, 30])), 10);
+        // 1.67 / 3.33 / 5: floors 1, 3, 5 and the leftover lot goes to the
+        // largest remainder
+        assert_eq!(allocation.fills, vec![(0, 2), (1, 3), (2, 5)]);
+        assert_eq!(steps(&allocation, AllocationStep::Leftover), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_top_order_is_filled_first_up_to_its_cap() {
+        let policy = ProRataPolicy { top_order: true, top_order_max: Some(6), ..Default::default() };
+        let allocation = ProRataAllocation { policy, lot_size: 1 }.allocate(&LevelView::new(&level(&[10, 10, 10])), 12);
+        assert_eq!(steps(&allocation, AllocationStep::TopOrder), vec![(0, 6)]);
+        // The other 6 is split 1 / 2.5 / 2.5 over the 4 / 10 / 10 still
+        // available; the tied remainders go in time priority
+        assert_eq!(allocation.fills, vec![(0, 7), (1, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn test_fifo_percent_goes_in_time_priority() {
+        let policy = ProRataPolicy { fifo_percent: 40, ..Default::default() };
+        let allocation = ProRataAllocation { policy, lot_size: 1 }.allocate(&LevelView::new(&level(&[3, 10, 10])), 10);
+        assert_eq!(steps(&allocation, AllocationStep::Fifo), vec![(0, 3), (1, 1)]);
+        assert_eq!(allocation.fills.iter().map(|f| f.1).sum::<u64>(), 10);
+    }
+
+    #[test]
+    fn test_min_allocation_drops_small_shares_to_leftover() {
+        let policy = ProRataPolicy { min_allocation: 3, leftover: LeftoverRule::TimePriority, ..Default::default() };
+        let allocation = ProRataAllocation { policy, lot_size: 1 }.allocate(&LevelView::new(&level(&[5, 45, 50])), 10);
+        assert!(steps(&allocation, AllocationStep::BelowMinimum).is_empty());
+        assert_eq!(steps(&allocation, AllocationStep::ProRata), vec![(1, 4), (2, 5)]);
+        // The oldest order had no share at all, but gets the leftover lot by time
+        assert_eq!(allocation.fills, vec![(0, 1), (1, 4), (2, 5)]);
+
+        let allocation = ProRataAllocation { policy, lot_size: 1 }.allocate(&LevelView::new(&level(&[20, 40, 40])), 10);
+        assert_eq!(steps(&allocation, AllocationStep::BelowMinimum), vec![(0, 2)]);
+        assert_eq!(allocation.fills, vec![(0, 2), (1, 4), (2, 4)]);
+    }
+
+    #[test]
+    fn test_shares_round_down_to_lots() {
+        let allocation = ProRataAllocation { policy: ProRataPolicy::default(), lot_size: 10 }.allocate(&LevelView::new(&level(&[100, 200])), 90);
+        // 30 / 60 are whole lots already; 35 / 70 would not be
+        assert_eq!(allocation.fills, vec![(0, 30), (1, 60)]);
+        let allocation = ProRataAllocation { policy: ProRataPolicy::default(), lot_size: 10 }.allocate(&LevelView::new(&level(&[100, 200])), 100);
+        assert_eq!(steps(&allocation, AllocationStep::ProRata), vec![(0, 30), (1, 60)]);
+        assert_eq!(steps(&allocation, AllocationStep::Leftover), vec![(1, 10)]);
+    }
+
+    #[test]
+    fn test_fifo_fills_are_recorded() {
+        let allocation = FifoAllocation.allocate(&LevelView::new(&level(&[3, 10])), 5);
+        assert_eq!(allocation.fills, vec![(0, 3), (1, 2)]);
+        assert_eq!(steps(&allocation, AllocationStep::Fifo), vec![(0, 3), (1, 2)]);
+    }
+
+    #[test]
+    fn test_size_priority_fills_largest_first() {
+        let allocation = SizePriorityAllocation.allocate(&LevelView::new(&level(&[5, 20, 10, 20])), 45);
+        assert_eq!(allocation.fills, vec![(1, 20), (3, 20), (2, 5)]);
+        assert_eq!(steps(&allocation, AllocationStep::SizePriority), vec![(1, 20), (3, 20), (2, 5)]);
+    }
+
+    fn lmm(makers: &[&str], percent: u8, residual: Box<dyn AllocationStrategy>) -> LmmAllocation {
+        LmmAllocation { makers: makers.iter().map(|m| m.to_string()).collect(), percent, residual }
+    }
+
+    #[test]
+    fn test_lmm_takes_its_percentage_before_the_residual() {
+        // u2 is the lead market maker: 40% of 50 first, then FIFO for the rest
+        let allocation = lmm(&["u2"], 40, Box::new(FifoAllocation)).allocate(&LevelView::new(&level(&[10, 10, 30])), 50);
+        assert_eq!(steps(&allocation, AllocationStep::LeadMarketMaker), vec![(2, 20)]);
+        assert_eq!(steps(&allocation, AllocationStep::Fifo), vec![(0, 10), (1, 10), (2, 10)]);
+        assert_eq!(allocation.fills, vec![(0, 10), (1, 10), (2, 30)]);
+    }
+
+    #[test]
+    fn test_lmm_is_capped_by_its_resting_size() {
+        let allocation = lmm(&["u1"], 100, Box::new(ProRataAllocation::default())).allocate(&LevelView::new(&level(&[10, 4])), 8);
+        assert_eq!(steps(&allocation, AllocationStep::LeadMarketMaker), vec![(1, 4)]);
+        assert_eq!(allocation.fills, vec![(0, 4), (1, 4)]);
+
+        // No maker at the level: the residual strategy gets everything
+        let allocation = lmm(&["u9"], 50, Box::new(FifoAllocation)).allocate(&LevelView::new(&level(&[10, 4])), 8);
+        assert_eq!(allocation, FifoAllocation.allocate(&LevelView::new(&level(&[10, 4])), 8));
+    }
+
+    #[test]
+    fn test_exact_for_quantities_beyond_f64_precision() {
+        // 2^53 + 1 cannot be represented as an f64
+        let big = (1u64 << 53) + 1;
+        let allocation = ProRataAllocation::default().allocate(&LevelView::new(&level(&[big, big])), big);
+        assert_eq!(allocation.fills, vec![(0, big / 2 + 1), (1, big / 2)]);
+    }
+
+    #[test]
+    fn test_level_larger_than_u64() {
+        let half = u64::MAX / 2 + 1;
+        let allocation = ProRataAllocation::default().allocate(&LevelView::new(&level(&[half, half])), 10);
+        assert_eq!(allocation.fills, vec![(0, 5), (1, 5)]);
+    }
+
+    fn policy() -> impl Strategy<Value = ProRataPolicy> {
+        (any::<bool>(), prop::option::of(1..50u64), 0..=100u8, 0..20u64, any::<bool>()).prop_map(
+            |(top_order, top_order_max, fifo_percent, min_allocation, by_time)| ProRataPolicy {
+                top_order,
+                top_order_max,
+                fifo_percent,
+                min_allocation,
+                leftover: if by_time { LeftoverRule::TimePriority } else { LeftoverRule::LargestRemainder },
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_allocations_sum_exactly_and_respect_resting_size(
+            sizes in prop::collection::vec(1..1_000u64, 1..20),
+            quantity in 1..20_000u64,
+            policy in policy(),
+            lot_size in 1..5u64,
+        ) {
+            let level = level(&sizes);
+            let allocation = ProRataAllocation { policy, lot_size }.allocate(&LevelView::new(&level), quantity);
+
+            let matched = quantity.min(sizes.iter().sum());
+            prop_assert_eq!(allocation.fills.iter().map(|f| f.1).sum::<u64>(), matched);
+            for &(slot, qty) in &allocation.fills {
+                prop_assert!(qty <= sizes[slot as usize]);
+            }
+
+            let allocated: u64 = allocation.decisions.iter()
+                .filter(|d| d.step != AllocationStep::BelowMinimum)
+                .map(|d| d.quantity)
+                .sum();
+            prop_assert_eq!(allocated, matched);
+        }
+
+        #[test]
+        fn prop_exact_for_huge_quantities(
+            sizes in prop::collection::vec(1..=u64::MAX, 1..20),
+            quantity in 1..u64::MAX,
+        ) {
+            let allocation = ProRataAllocation::default().allocate(&LevelView::new(&level(&sizes)), quantity);
+            let total: u128 = sizes.iter().map(|&s| s as u128).sum();
+            let matched = (quantity as u128).min(total);
+            prop_assert_eq!(allocation.fills.iter().map(|f| f.1 as u128).sum::<u128>(), matched);
+            for &(slot, qty) in &allocation.fills {
+                prop_assert!(qty <= sizes[slot as usize]);
+            }
+        }
+
+        #[test]
+        fn prop_allocation_is_deterministic(
+            sizes in prop::collection::vec(1..1_000u64, 1..20),
+            quantity in 1..20_000u64,
+            policy in policy(),
+        ) {
+            let level = level(&sizes);
+            let strategy = ProRataAllocation { policy, lot_size: 1 };
+            prop_assert_eq!(strategy.allocate(&LevelView::new(&level), quantity), strategy.allocate(&LevelView::new(&level), quantity));
+        }
+
+        #[test]
+        fn prop_every_strategy_fills_what_it_can(
+            sizes in prop::collection::vec(1..1_000u64, 1..20),
+            quantity in 1..20_000u64,
+            percent in 0..=100u8,
+        ) {
+            let level = level(&sizes);
+            let strategies: Vec<Box<dyn AllocationStrategy>> = vec![
+                Box::new(FifoAllocation),
+                Box::new(SizePriorityAllocation),
+                Box::new(lmm(&["u0", "u3"], percent, Box::new(ProRataAllocation::default()))),
+            ];
+            for strategy in strategies {
+                let allocation = strategy.allocate(&LevelView::new(&level), quantity);
+                prop_assert_eq!(allocation.fills.iter().map(|f| f.1).sum::<u64>(), quantity.min(sizes.iter().sum()));
+                for &(slot, qty) in &allocation.fills {
+                    prop_assert!(qty <= sizes[slot as usize]);
+                }
+            }
+        }
+    }
+}
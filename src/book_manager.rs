@@ -0,0 +1,573 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::engine::{AmendError, CancelError, Depth, MatchingMode, OrderBook, Quote};
+use crate::instrument::{Instrument, InstrumentRegistry, TradingStatus};
+use crate::market_data::{MarketDataFeed, MarketDataSubscription, OrderFeedSubscription};
+use crate::models::{ExecType, ExecutionReport, Order, Trade};
+use crate::price::PriceFormat;
+use crate::redis_writer::{EventSender, RedisEvent};
+
+const SHARD_QUEUE_DEPTH: usize = 1024;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Execution reports buffered for each `subscribe_reports` receiver.
+const REPORT_BUFFER: usize = 4096;
+
+/// The book's task is no longer running, so the command was not applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BookStopped;
+
+/// The result of a book command together with the execution reports for
+/// every order it touched.
+#[derive(Debug)]
+pub struct BookOutcome<T> {
+    pub result: T,
+    pub reports: Vec<ExecutionReport>,
+}
+
+enum BookCommand {
+    Submit {
+        order: Order,
+        reply: oneshot::Sender<BookOutcome<Vec<Trade>>>,
+    },
+    Cancel {
+        order_id: String,
+        user_id: String,
+        reply: oneshot::Sender<BookOutcome<Result<Order, CancelError>>>,
+    },
+    Amend {
+        order_id: String,
+        user_id: String,
+        new_price: Option<u64>,
+        new_quantity: Option<u64>,
+        reply: oneshot::Sender<BookOutcome<Result<Vec<Trade>, AmendError>>>,
+    },
+    SetStatus {
+        status: TradingStatus,
+        reply: oneshot::Sender<Instrument>,
+    },
+    SetMode {
+        mode: MatchingMode,
+        reply: oneshot::Sender<Result<Instrument, String>>,
+    },
+    Depth {
+        levels: usize,
+        reply: oneshot::Sender<Depth>,
+    },
+    Subscribe {
+        reply: oneshot::Sender<MarketDataSubscription>,
+    },
+    SubscribeOrders {
+        reply: oneshot::Sender<OrderFeedSubscription>,
+    },
+    Quote {
+        reply: oneshot::Sender<Quote>,
+    },
+}
+
+/// Sends commands to the task that owns one symbol's `OrderBook`.
+///
+/// Commands for a symbol are applied strictly in the order they arrive, while
+/// different symbols are matched in parallel on their own tasks.
+#[derive(Clone)]
+pub struct BookHandle {
+    tx: mpsc::Sender<BookCommand>,
+    /// Only the book task writes this, so status and mode changes take
+    /// effect in command order; readers see the latest applied state.
+    instrument: Arc<RwLock<Instrument>>,
+}
+
+impl BookHandle {
+    fn spawn(instrument: Instrument, events: EventSender, reports: broadcast::Sender<ExecutionReport>) -> Self {
+        let (tx, rx) = mpsc::channel(SHARD_QUEUE_DEPTH);
+        let mut book = OrderBook::new(instrument.matching_mode);
+        book.tick_size = instrument.price_format.tick_size;
+        book.strategy = instrument.allocation_strategy();
+        let instrument = Arc::new(RwLock::new(instrument));
+        tokio::spawn(run_book(book, instrument.clone(), rx, events, reports));
+        Self { tx, instrument }
+    }
+
+    /// Reference data and current status of the symbol this book trades.
+    pub fn instrument(&self) -> Instrument {
+        self.instrument.read().unwrap().clone()
+    }
+
+    /// How prices for this symbol convert to and from decimal strings.
+    pub fn price_format(&self) -> PriceFormat {
+        self.instrument.read().unwrap().price_format
+    }
+
+    pub async fn submit_order(&self, order: Order) -> Result<BookOutcome<Vec<Trade>>, BookStopped> {
+        self.request(|reply| BookCommand::Submit { order, reply }).await
+    }
+
+    /// Cancels `order_id` if it was placed by `user_id`.
+    pub async fn cancel_order(&self, order_id: String, user_id: String) -> Result<BookOutcome<Result<Order, CancelError>>, BookStopped> {
+        self.request(|reply| BookCommand::Cancel { order_id, user_id, reply }).await
+    }
+
+    /// Amends `order_id` if it was placed by `user_id`.
+    pub async fn amend_order(&self, order_id: String, user_id: String, new_price: Option<u64>, new_quantity: Option<u64>) -> Result<BookOutcome<Result<Vec<Trade>, AmendError>>, BookStopped> {
+        self.request(|reply| BookCommand::Amend { order_id, user_id, new_price, new_quantity, reply }).await
+    }
+
+    /// Opens, halts or closes the instrument and returns its new state.
+    pub async fn set_trading_status(&self, status: TradingStatus) -> Result<Instrument, BookStopped> {
+        self.request(|reply| BookCommand::SetStatus { status, reply }).await
+    }
+
+    /// Switches the matching mode. The instrument must be halted, so no order
+    /// is ever matched half under one mode and half under the other.
+    pub async fn set_matching_mode(&self, mode: MatchingMode) -> Result<Result<Instrument, String>, BookStopped> {
+        self.request(|reply| BookCommand::SetMode { mode, reply }).await
+    }
+
+    /// Aggregated top `levels` of each side, as of every command sent
+    /// before this one.
+    pub async fn depth(&self, levels: usize) -> Result<Depth, BookStopped> {
+        self.request(|reply| BookCommand::Depth { levels, reply }).await
+    }
+
+    /// Best bid and offer and the last trade.
+    pub async fn quote(&self) -> Result<Quote, BookStopped> {
+        self.request(|reply| BookCommand::Quote { reply }).await
+    }
+
+    /// Subscribes to the book's market data: a full snapshot, then level
+    /// updates and trade prints.
+    pub async fn subscribe_market_data(&self) -> Result<MarketDataSubscription, BookStopped> {
+        self.request(|reply| BookCommand::Subscribe { reply }).await
+    }
+
+    /// Subscribes to the book's order-by-order feed: every displayed order,
+    /// then add, execute, cancel and replace events.
+    pub async fn subscribe_order_feed(&self) -> Result<OrderFeedSubscription, BookStopped> {
+        self.request(|reply| BookCommand::SubscribeOrders { reply }).await
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> BookCommand) -> Result<T, BookStopped> {
+        let (reply, response) = oneshot::channel();
+        self.tx.send(command(reply)).await.map_err(|_| BookStopped)?;
+        response.await.map_err(|_| BookStopped)
+    }
+}
+
+/// Owns a single `OrderBook` and applies commands to it one at a time.
+/// Trades, cancels and execution reports are handed to the Redis publisher,
+/// execution reports to the report subscribers, and trades and level updates
+/// to the market data feed, before the caller is answered, so publication
+/// order always matches matching order. DAY/GTD orders are expired between
+/// commands. While the publisher's queue is full the book waits for it.
+///
+/// Orders and amends are refused unless the instrument is open. Callers
+/// check the status up front too, but only the check here is ordered with
+/// respect to halts.
+async fn run_book(
+    mut book: OrderBook,
+    instrument: Arc<RwLock<Instrument>>,
+    mut rx: mpsc::Receiver<BookCommand>,
+    events: EventSender,
+    reports: broadcast::Sender<ExecutionReport>,
+) {
+    let mut expiry_check = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    let mut feed = MarketDataFeed::default();
+    let symbol = instrument.read().unwrap().symbol.clone();
+
+    loop {
+        let command = tokio::select! {
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            _ = expiry_check.tick() => {
+                book.expire_orders(chrono::Utc::now());
+                finish(&mut book, &symbol, &events, &reports, &mut feed, ()).await;
+                continue;
+            }
+        };
+
+        match command {
+            BookCommand::Submit { order, reply } => {
+                let open = instrument.read().unwrap().check_open();
+                if let Err(reason) = open {
+                    let report = ExecutionReport::new(&order, ExecType::Rejected).with_reason(&reason);
+                    events.send(RedisEvent::ExecutionReport(report.clone())).await;
+                    let _ = reports.send(report.clone());
+                    let _ = reply.send(BookOutcome { result: vec![], reports: vec![report] });
+                    continue;
+                }
+                let trades = book.submit_order(order);
+                publish_trades(&events, &mut feed, &trades).await;
+                let outcome = finish(&mut book, &symbol, &events, &reports, &mut feed, trades).await;
+                let _ = reply.send(outcome);
+            }
+            BookCommand::Cancel { order_id, user_id, reply } => {
+                let cancelled = match book.get_order(&order_id) {
+                    Some(order) if order.user_id != user_id => Err(CancelError::NotOwner),
+                    _ => book.cancel_order(&order_id).ok_or(CancelError::NotFound),
+                };
+                if let Ok(order) = &cancelled {
+                    events.send(RedisEvent::Cancel(order.clone())).await;
+                }
+                let outcome = finish(&mut book, &symbol, &events, &reports, &mut feed, cancelled).await;
+                let _ = reply.send(outcome);
+            }
+            BookCommand::Amend { order_id, user_id, new_price, new_quantity, reply } => {
+                let result = match instrument.read().unwrap().check_open() {
+                    Err(_) => Err(AmendError::NotOpen),
+                    Ok(()) if book.get_order(&order_id).is_some_and(|o| o.user_id != user_id) => Err(AmendError::NotOwner),
+                    Ok(()) => book.amend_order(&order_id, new_price, new_quantity),
+                };
+                if let Ok(trades) = &result {
+                    publish_trades(&events, &mut feed, trades).await;
+                }
+                let outcome = finish(&mut book, &symbol, &events, &reports, &mut feed, result).await;
+                let _ = reply.send(outcome);
+            }
+            BookCommand::SetStatus { status, reply } => {
+                let mut instrument = instrument.write().unwrap();
+                instrument.status = status;
+                let _ = reply.send(instrument.clone());
+            }
+            BookCommand::SetMode { mode, reply } => {
+                let mut instrument = instrument.write().unwrap();
+                let result = if instrument.status == TradingStatus::Halted {
+                    instrument.matching_mode = mode;
+                    book.strategy = instrument.allocation_strategy();
+                    Ok(instrument.clone())
+                } else {
+                    Err(format!("{} must be halted to change its matching mode", instrument.symbol))
+                };
+                let _ = reply.send(result);
+            }
+            BookCommand::Depth { levels, reply } => {
+                let _ = reply.send(book.depth(levels));
+            }
+            BookCommand::Subscribe { reply } => {
+                let _ = reply.send(feed.subscribe(&book));
+            }
+            BookCommand::SubscribeOrders { reply } => {
+                let _ = reply.send(feed.subscribe_orders(&book));
+            }
+            BookCommand::Quote { reply } => {
+                let _ = reply.send(book.quote());
+            }
+        }
+    }
+}
+
+/// Drains the book's market data, quote changes, execution reports and
+/// allocation audit records, publishes them and pairs the reports with the
+/// command's result.
+async fn finish<T>(
+    book: &mut OrderBook,
+    symbol: &str,
+    events: &EventSender,
+    subscribers: &broadcast::Sender<ExecutionReport>,
+    feed: &mut MarketDataFeed,
+    result: T,
+) -> BookOutcome<T> {
+    feed.publish_book(book);
+    if let Some(quote) = book.take_quote_change() {
+        events.send(RedisEvent::Quote(symbol.to_string(), quote)).await;
+    }
+    for record in book.take_allocations() {
+        events.send(RedisEvent::Allocation(record)).await;
+    }
+    let reports = book.take_reports();
+    for report in &reports {
+        events.send(RedisEvent::ExecutionReport(report.clone())).await;
+        // Nobody listening is fine
+        let _ = subscribers.send(report.clone());
+    }
+    BookOutcome { result, reports }
+}
+
+async fn publish_trades(events: &EventSender, feed: &mut MarketDataFeed, trades: &[Trade]) {
+    for trade in trades {
+        events.send(RedisEvent::Trade(trade.clone())).await;
+        feed.publish_trade(trade);
+    }
+}
+
+/// Routes orders to one `OrderBook` task per tradable symbol. Clones share
+/// the same book tasks.
+#[derive(Clone)]
+pub struct BookManager {
+    books: BTreeMap<String, BookHandle>,
+    reports: broadcast::Sender<ExecutionReport>,
+}
+
+impl BookManager {
+    /// Spawns a book task for every instrument in the registry, each
+    /// matching in its instrument's configured mode. Must be called from
+    /// within a Tokio runtime.
+    pub fn new(registry: &InstrumentRegistry, events: EventSender) -> Self {
+        let (reports, _) = broadcast::channel(REPORT_BUFFER);
+        let books = registry.iter()
+            .map(|instrument| (instrument.symbol.clone(), BookHandle::spawn(instrument.clone(), events.clone(), reports.clone())))
+            .collect();
+        Self { books, reports }
+    }
+
+    /// Every tradable instrument, in symbol order.
+    pub fn instruments(&self) -> Vec<Instrument> {
+        self.books.values().map(BookHandle::instrument).collect()
+    }
+
+    /// Returns the book for `symbol`, or `None` if the symbol is not traded.
+    pub fn book(&self, symbol: &str) -> Option<&BookHandle> {
+        self.books.get(symbol)
+    }
+
+    /// Every execution report from every book from now on, each book's in
+    /// the order it produced them.
+    pub fn subscribe_reports(&self) -> broadcast::Receiver<ExecutionReport> {
+        self.reports.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed_handler::BookReplica;
+    use crate::market_data::MarketDataEvent;
+    use crate::models::{ExecType, Side, OrderType, TimeInForce};
+    use crate::redis_writer::{event_queue, EVENT_QUEUE_DEPTH};
+
+    fn make_order(user: &str, symbol: &str, price: u64, qty: u64, side: Side) -> Order {
+        Order::new(
+            user.to_string(),
+            symbol.to_string(),
+            side,
+            OrderType::Limit,
+            Some(price),
+            qty,
+        )
+    }
+
+    fn manager() -> (BookManager, mpsc::Receiver<RedisEvent>) {
+        let (events, rx) = event_queue(EVENT_QUEUE_DEPTH);
+        let registry = InstrumentRegistry::from_json(r#"[
+            {"symbol": "AAPL", "price_scale": 2, "tick_size": "0.01"},
+            {"symbol": "MSFT", "price_scale": 2, "tick_size": "0.05", "matching_mode": "pro_rata"}
+        ]"#).unwrap();
+        let manager = BookManager::new(&registry, events);
+        (manager, rx)
+    }
+
+    #[tokio::test]
+    async fn test_orders_for_different_symbols_do_not_match() {
+        let (manager, _events) = manager();
+        let msft_sell = make_order("s1", "MSFT", 100, 10, Side::Sell);
+        manager.book("MSFT").unwrap().submit_order(msft_sell.clone()).await.unwrap();
+        let trades = manager.book("AAPL").unwrap().submit_order(make_order("b1", "AAPL", 100, 10, Side::Buy)).await.unwrap().result;
+
+        assert!(trades.is_empty());
+        let resting = manager.book("MSFT").unwrap().cancel_order(msft_sell.id, "s1".to_string()).await.unwrap().result.unwrap();
+        assert_eq!(resting.quantity, 10);
+    }
+
+    #[tokio::test]
+    async fn test_trades_carry_their_symbol_and_are_published() {
+        let (manager, mut events) = manager();
+        let book = manager.book("MSFT").unwrap();
+        book.submit_order(make_order("s1", "MSFT", 100, 10, Side::Sell)).await.unwrap();
+        let outcome = book.submit_order(make_order("b1", "MSFT", 100, 10, Side::Buy)).await.unwrap();
+        let trades = outcome.result;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].symbol, "MSFT");
+        assert_eq!(outcome.reports.len(), 3);
+
+        let published: Vec<RedisEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert!(published.iter().any(|e| matches!(e, RedisEvent::Trade(t) if *t == trades[0])));
+        let reports = published.iter().filter(|e| matches!(e, RedisEvent::ExecutionReport(_))).count();
+        assert_eq!(reports, 4);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_amend_through_handle() {
+        let (manager, mut events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        let order = make_order("b1", "AAPL", 99, 10, Side::Buy);
+        book.submit_order(order.clone()).await.unwrap();
+
+        assert_eq!(book.amend_order(order.id.clone(), "b2".to_string(), None, Some(4)).await.unwrap().result, Err(AmendError::NotOwner));
+        assert!(book.amend_order(order.id.clone(), "b1".to_string(), None, Some(4)).await.unwrap().result.unwrap().is_empty());
+        assert!(matches!(book.cancel_order(order.id.clone(), "b2".to_string()).await.unwrap().result, Err(CancelError::NotOwner)));
+        let cancelled = book.cancel_order(order.id.clone(), "b1".to_string()).await.unwrap();
+        assert_eq!(cancelled.result.unwrap().quantity, 4);
+        assert_eq!(cancelled.reports[0].exec_type, ExecType::Cancelled);
+        assert!(matches!(book.cancel_order(order.id, "b1".to_string()).await.unwrap().result, Err(CancelError::NotFound)));
+
+        let published: Vec<RedisEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert!(published.iter().any(|e| matches!(e, RedisEvent::Cancel(_))));
+    }
+
+    #[tokio::test]
+    async fn test_books_enforce_their_tick_size() {
+        let (manager, _events) = manager();
+        let book = manager.book("MSFT").unwrap();
+        assert_eq!(book.price_format(), PriceFormat::new(2, 5));
+
+        let outcome = book.submit_order(make_order("b1", "MSFT", 10003, 1, Side::Buy)).await.unwrap();
+        assert_eq!(outcome.reports[0].exec_type, ExecType::Rejected);
+        let outcome = book.submit_order(make_order("b1", "MSFT", 10005, 1, Side::Buy)).await.unwrap();
+        assert_eq!(outcome.reports[0].exec_type, ExecType::New);
+    }
+
+    #[tokio::test]
+    async fn test_books_use_their_instrument_mode() {
+        let (manager, _events) = manager();
+        assert_eq!(manager.book("AAPL").unwrap().instrument().matching_mode, MatchingMode::Fifo);
+
+        // MSFT matches pro-rata: 6 is split 4/2 across resting 10 and 5
+        let book = manager.book("MSFT").unwrap();
+        book.submit_order(make_order("s1", "MSFT", 100, 10, Side::Sell)).await.unwrap();
+        book.submit_order(make_order("s2", "MSFT", 100, 5, Side::Sell)).await.unwrap();
+        let trades = book.submit_order(make_order("b1", "MSFT", 100, 6, Side::Buy)).await.unwrap().result;
+        let fills: Vec<(String, u64)> = trades.into_iter().map(|t| (t.seller, t.quantity)).collect();
+        assert_eq!(fills, vec![("s1".to_string(), 4), ("s2".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_halt_and_switch_matching_mode() {
+        let (manager, _events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        let resting = make_order("s1", "AAPL", 100, 10, Side::Sell);
+        book.submit_order(resting.clone()).await.unwrap();
+
+        assert!(book.set_matching_mode(MatchingMode::ProRata).await.unwrap().is_err());
+
+        let halted = book.set_trading_status(TradingStatus::Halted).await.unwrap();
+        assert_eq!(halted.status, TradingStatus::Halted);
+        let outcome = book.submit_order(make_order("b1", "AAPL", 100, 5, Side::Buy)).await.unwrap();
+        assert!(outcome.result.is_empty());
+        assert_eq!(outcome.reports[0].exec_type, ExecType::Rejected);
+        assert_eq!(outcome.reports[0].reason.as_deref(), Some("AAPL is halted"));
+        assert_eq!(book.amend_order(resting.id.clone(), "s1".to_string(), None, Some(5)).await.unwrap().result, Err(AmendError::NotOpen));
+
+        let switched = book.set_matching_mode(MatchingMode::ProRata).await.unwrap().unwrap();
+        assert_eq!(switched.matching_mode, MatchingMode::ProRata);
+        assert_eq!(book.instrument().matching_mode, MatchingMode::ProRata);
+
+        // Cancels still work while halted
+        assert!(book.cancel_order(resting.id, "s1".to_string()).await.unwrap().result.is_ok());
+        book.set_trading_status(TradingStatus::Open).await.unwrap();
+        let outcome = book.submit_order(make_order("b1", "AAPL", 100, 5, Side::Buy)).await.unwrap();
+        assert_eq!(outcome.reports[0].exec_type, ExecType::New);
+    }
+
+    #[tokio::test]
+    async fn test_orders_up_to_an_unlimited_max_quantity() {
+        // AAPL sets no max_quantity, so one order can be as large as u64::MAX
+        let (manager, _events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        assert_eq!(book.instrument().max_quantity, u64::MAX);
+        let half = u64::MAX / 2 + 1;
+        book.submit_order(make_order("s1", "AAPL", 100, half, Side::Sell)).await.unwrap();
+        let last = make_order("s2", "AAPL", 100, half, Side::Sell);
+        book.submit_order(last.clone()).await.unwrap();
+        assert_eq!(book.depth(1).await.unwrap().asks[0].quantity, u64::MAX);
+
+        let fok = make_order("b1", "AAPL", 100, u64::MAX, Side::Buy).with_time_in_force(TimeInForce::Fok);
+        let trades = book.submit_order(fok).await.unwrap().result;
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<u64>(), u64::MAX);
+        let resting = book.cancel_order(last.id, "s2".to_string()).await.unwrap().result.unwrap();
+        assert_eq!(resting.quantity, 1);
+    }
+
+    #[tokio::test]
+    async fn test_depth_through_handle() {
+        let (manager, _events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        book.submit_order(make_order("b1", "AAPL", 99, 10, Side::Buy)).await.unwrap();
+        book.submit_order(make_order("b2", "AAPL", 99, 5, Side::Buy)).await.unwrap();
+        book.submit_order(make_order("s1", "AAPL", 101, 3, Side::Sell)).await.unwrap();
+
+        let depth = book.depth(5).await.unwrap();
+        assert_eq!((depth.bids[0].price, depth.bids[0].quantity, depth.bids[0].order_count), (99, 15, 2));
+        assert_eq!((depth.asks[0].price, depth.asks[0].quantity, depth.asks[0].order_count), (101, 3, 1));
+    }
+
+    #[tokio::test]
+    async fn test_market_data_snapshot_then_updates() {
+        let (manager, _events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        book.submit_order(make_order("s1", "AAPL", 101, 10, Side::Sell)).await.unwrap();
+
+        let mut subscription = book.subscribe_market_data().await.unwrap();
+        assert_eq!(subscription.sequence, 1);
+        assert_eq!(subscription.snapshot.asks[0].quantity, 10);
+
+        book.submit_order(make_order("b1", "AAPL", 101, 4, Side::Buy)).await.unwrap();
+        let trade = subscription.updates.recv().await.unwrap();
+        assert_eq!(trade.sequence, 2);
+        assert!(matches!(trade.event, MarketDataEvent::Trade(t) if t.quantity == 4));
+        let level = subscription.updates.recv().await.unwrap();
+        assert_eq!(level.sequence, 3);
+        assert!(matches!(level.event, MarketDataEvent::Level(u) if u.level.quantity == 6));
+    }
+
+    #[tokio::test]
+    async fn test_order_feed_replica_tracks_the_book() {
+        let (manager, _events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        let resting = make_order("s1", "AAPL", 101, 10, Side::Sell);
+        book.submit_order(resting.clone()).await.unwrap();
+
+        let mut subscription = book.subscribe_order_feed().await.unwrap();
+        let mut replica = BookReplica::from_snapshot(subscription.sequence, &subscription.snapshot).unwrap();
+        book.submit_order(make_order("b1", "AAPL", 101, 4, Side::Buy)).await.unwrap();
+        book.cancel_order(resting.id, "s1".to_string()).await.unwrap();
+        for _ in 0..2 {
+            replica.apply(&subscription.updates.recv().await.unwrap()).unwrap();
+        }
+
+        assert_eq!(replica.sequence(), 3);
+        assert_eq!(replica.depth(10), book.depth(10).await.unwrap());
+        assert!(replica.orders().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_quote_changes_are_published() {
+        let (manager, mut events) = manager();
+        let book = manager.book("AAPL").unwrap();
+        book.submit_order(make_order("b1", "AAPL", 99, 10, Side::Buy)).await.unwrap();
+        book.submit_order(make_order("b2", "AAPL", 98, 10, Side::Buy)).await.unwrap();
+
+        let quote = book.quote().await.unwrap();
+        assert_eq!(quote.bid.map(|l| l.price), Some(99));
+        let published: Vec<RedisEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let quotes: Vec<&Quote> = published.iter()
+            .filter_map(|e| match e {
+                RedisEvent::Quote(symbol, quote) if symbol == "AAPL" => Some(quote),
+                _ => None,
+            })
+            .collect();
+        // The order behind the best bid leaves the quote unchanged
+        assert_eq!(quotes.len(), 1);
+        assert_eq!(quotes[0].bid.as_ref().map(|l| l.quantity), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_symbol_has_no_book() {
+        let (manager, _events) = manager();
+        assert!(manager.book("TSLA").is_none());
+        let symbols: Vec<String> = manager.instruments().into_iter().map(|i| i.symbol).collect();
+        assert_eq!(symbols, vec!["AAPL", "MSFT"]);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_book_is_an_error() {
+        let (manager, _events) = manager();
+        let (tx, _) = mpsc::channel(1);
+        let book = BookHandle { tx, instrument: Arc::new(RwLock::new(manager.instruments().remove(0))) };
+        assert!(matches!(book.submit_order(make_order("b1", "AAPL", 100, 10, Side::Buy)).await, Err(BookStopped)));
+    }
+}
@@ -1,17 +1,184 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 
-use crate::models::{Order, Trade, Side, OrderType};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::allocation::{AllocationStrategy, FifoAllocation, LevelView, ProRataAllocation, SizePriorityAllocation};
+use crate::models::{AllocationRecord, AllocationStep, ExecType, ExecutionReport, Order, Trade, Side, OrderType, PostOnly, SelfTradePrevention, TimeInForce};
+use crate::price_level::PriceLevel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MatchingMode {
     Fifo,
     ProRata,
+    SizePriority,
+}
+
+impl MatchingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchingMode::Fifo => "fifo",
+            MatchingMode::ProRata => "pro_rata",
+            MatchingMode::SizePriority => "size_priority",
+        }
+    }
+
+    /// The mode's allocation strategy with default settings.
+    pub fn strategy(&self) -> Box<dyn AllocationStrategy> {
+        match self {
+            MatchingMode::Fifo => Box::new(FifoAllocation),
+            MatchingMode::ProRata => Box::new(ProRataAllocation::default()),
+            MatchingMode::SizePriority => Box::new(SizePriorityAllocation),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CancelError {
+    NotFound,
+    /// The order was placed by another user.
+    NotOwner,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmendError {
+    NotFound,
+    InvalidQuantity,
+    /// A stop order cannot be repriced, and a new price must be on tick.
+    InvalidPrice,
+    /// The new price would make a post-only order take liquidity.
+    WouldTakeLiquidity,
+    /// The instrument is halted or closed.
+    NotOpen,
+    /// The order was placed by another user.
+    NotOwner,
+}
+
+/// Aggregated resting interest at one price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthLevel {
+    pub price: u64,
+    /// Shown quantity; the hidden reserve of iceberg orders is left out.
+    pub quantity: u64,
+    pub order_count: usize,
+}
+
+/// Top of the book, best price first on each side.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Depth {
+    pub bids: Vec<DepthLevel>,
+    pub asks: Vec<DepthLevel>,
+}
+
+/// Top of the book and the last trade.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quote {
+    pub bid: Option<DepthLevel>,
+    pub ask: Option<DepthLevel>,
+    pub last_trade_price: Option<u64>,
+    pub last_trade_quantity: Option<u64>,
+}
+
+impl Quote {
+    /// Best ask minus best bid, when both sides are quoted.
+    pub fn spread(&self) -> Option<u64> {
+        match (&self.bid, &self.ask) {
+            (Some(bid), Some(ask)) => ask.price.checked_sub(bid.price),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelAction {
+    Add,
+    Change,
+    Delete,
+}
+
+impl LevelAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LevelAction::Add => "add",
+            LevelAction::Change => "change",
+            LevelAction::Delete => "delete",
+        }
+    }
+}
+
+/// A change to one aggregated price level. A deleted level has zero
+/// quantity and no orders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelUpdate {
+    pub side: Side,
+    pub action: LevelAction,
+    pub level: DepthLevel,
+}
+
+/// One order-by-order change to the displayed book, in the spirit of ITCH.
+///
+/// Replaying these in order rebuilds the book exactly, queue positions
+/// included. Quantities are displayed quantities: an iceberg's hidden
+/// reserve is never shown, and a fresh slice appears as a new `Add`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderEvent {
+    /// The order is displayed at the back of the queue at `price`.
+    Add { order_id: String, side: Side, price: u64, quantity: u64 },
+    /// The order traded `quantity`. It leaves the book once nothing is
+    /// displayed.
+    Execute { order_id: String, quantity: u64, trade_sequence: u64 },
+    /// The displayed quantity shrank by `quantity` without trading, through
+    /// a cancel, expiry, amend or self-trade prevention. The order leaves
+    /// the book once nothing is displayed.
+    Cancel { order_id: String, quantity: u64 },
+    /// The order was amended and lost its place: it now shows `quantity` at
+    /// the back of the queue at `price`, on the same side.
+    Replace { order_id: String, price: u64, quantity: u64 },
+}
+
+/// Where a resting order lives: its side, price level and slot in that level.
+/// Untriggered stop orders live in the trigger book, keyed by trigger price.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OrderLocation {
+    side: Side,
+    price: u64,
+    slot: u64,
+    stop: bool,
 }
 
 pub struct OrderBook {
-    pub buy_book: BTreeMap<u64, VecDeque<Order>>,  // descending order
-    pub sell_book: BTreeMap<u64, VecDeque<Order>>, // ascending order
-    pub mode: MatchingMode,
+    pub buy_book: BTreeMap<u64, PriceLevel>,  // descending order
+    pub sell_book: BTreeMap<u64, PriceLevel>, // ascending order
+    /// Untriggered stop orders keyed by trigger price.
+    pub buy_stops: BTreeMap<u64, PriceLevel>,
+    pub sell_stops: BTreeMap<u64, PriceLevel>,
+    /// Shares each incoming order among the resting orders at a price.
+    pub strategy: Box<dyn AllocationStrategy>,
+    /// Smallest price increment in scaled units. Prices must be a multiple
+    /// of it, and post-only orders slide by one tick.
+    pub tick_size: u64,
+    /// Best prices, kept up to date as levels are added and removed.
+    best_bid: Option<u64>,
+    best_ask: Option<u64>,
+    last_trade_price: Option<u64>,
+    last_trade_quantity: Option<u64>,
+    /// The quote as it was last taken by `take_quote_change`.
+    announced_quote: Quote,
+    index: HashMap<String, OrderLocation>,
+    next_slot: u64,
+    trade_sequence: u64,
+    reports: Vec<ExecutionReport>,
+    allocations: Vec<AllocationRecord>,
+    /// Price levels that may have changed since the last level updates were
+    /// taken, and the levels as they were last announced.
+    touched: Vec<(Side, u64)>,
+    announced: HashMap<(Side, u64), DepthLevel>,
+    order_events: Vec<OrderEvent>,
+    /// Expiry times of resting DAY/GTD orders. Entries for orders that have
+    /// since left the book are skipped when they come due.
+    expiries: BTreeSet<(DateTime<Utc>, String)>,
 }
 
 impl OrderBook {
@@ -19,17 +186,474 @@ impl OrderBook {
         Self {
             buy_book: BTreeMap::new(),
             sell_book: BTreeMap::new(),
-            mode,
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            strategy: mode.strategy(),
+            tick_size: 1,
+            best_bid: None,
+            best_ask: None,
+            last_trade_price: None,
+            last_trade_quantity: None,
+            announced_quote: Quote::default(),
+            index: HashMap::new(),
+            next_slot: 0,
+            trade_sequence: 0,
+            reports: Vec::new(),
+            allocations: Vec::new(),
+            touched: Vec::new(),
+            announced: HashMap::new(),
+            order_events: Vec::new(),
+            expiries: BTreeSet::new(),
+        }
+    }
+
+    pub fn submit_order(&mut self, mut order: Order) -> Vec<Trade> {
+        if let Some(reason) = self.validate(&order) {
+            self.reports.push(ExecutionReport::new(&order, ExecType::Rejected).with_reason(reason));
+            return vec![];
+        }
+
+        // Stop-limit orders are checked for post-only when they trigger
+        if !order.is_stop() {
+            match self.post_only_price(&order) {
+                Ok(price) => order.price = price,
+                Err(reason) => {
+                    self.reports.push(ExecutionReport::new(&order, ExecType::Rejected).with_reason(reason));
+                    return vec![];
+                }
+            }
+        }
+
+        self.reports.push(ExecutionReport::new(&order, ExecType::New));
+
+        let mut trades = self.process(order);
+        self.trigger_stops(&mut trades);
+        trades
+    }
+
+    /// Removes every resting order whose DAY/GTD expiry is at or before `now`
+    /// and reports it as expired.
+    pub fn expire_orders(&mut self, now: DateTime<Utc>) -> Vec<Order> {
+        let mut expired = vec![];
+
+        while let Some((expiry, order_id)) = self.expiries.first().cloned() {
+            if expiry > now {
+                break;
+            }
+            self.expiries.pop_first();
+
+            // Stop orders can expire before they are ever triggered
+            let still_due = self.get_order(&order_id).and_then(|o| o.expires_at()) == Some(expiry);
+            if !still_due {
+                continue;
+            }
+
+            let order = self.remove_resting(&order_id).unwrap();
+            self.reports.push(ExecutionReport::new(&order, ExecType::Expired));
+            expired.push(order);
+        }
+
+        expired
+    }
+
+    /// Drains the execution reports produced since the last call, in the
+    /// order the state transitions happened.
+    pub fn take_reports(&mut self) -> Vec<ExecutionReport> {
+        std::mem::take(&mut self.reports)
+    }
+
+    /// Drains the allocation audit records produced since the last call.
+    pub fn take_allocations(&mut self) -> Vec<AllocationRecord> {
+        std::mem::take(&mut self.allocations)
+    }
+
+    /// Drains the changes to the aggregated book since the last call, one
+    /// update per level whose shown quantity or order count differs from
+    /// what was last announced, in the order the levels were first touched.
+    pub fn take_level_updates(&mut self) -> Vec<LevelUpdate> {
+        let mut updates = Vec::new();
+        for (side, price) in std::mem::take(&mut self.touched) {
+            let current = self.levels(side, false).get(&price).map(|level| DepthLevel {
+                price,
+                quantity: level.visible_quantity(),
+                order_count: level.len(),
+            });
+            let action = match (self.announced.get(&(side, price)), &current) {
+                (None, None) => continue,
+                (Some(announced), Some(current)) if announced == current => continue,
+                (None, Some(_)) => LevelAction::Add,
+                (Some(_), Some(_)) => LevelAction::Change,
+                (Some(_), None) => LevelAction::Delete,
+            };
+            let level = match current {
+                Some(level) => {
+                    self.announced.insert((side, price), level.clone());
+                    level
+                }
+                None => {
+                    self.announced.remove(&(side, price));
+                    DepthLevel { price, quantity: 0, order_count: 0 }
+                }
+            };
+            updates.push(LevelUpdate { side, action, level });
+        }
+        updates
+    }
+
+    /// Drains the order-by-order events produced since the last call.
+    pub fn take_order_events(&mut self) -> Vec<OrderEvent> {
+        std::mem::take(&mut self.order_events)
+    }
+
+    /// Every displayed order as `Add` events, bids best price first and then
+    /// asks best price first, each level in time priority. Replaying them
+    /// into an empty book rebuilds this one.
+    pub fn order_snapshot(&self) -> Vec<OrderEvent> {
+        let bids = self.buy_book.iter().rev();
+        let asks = self.sell_book.iter();
+        bids.chain(asks)
+            .flat_map(|(&price, level)| level.iter().map(move |(_, order)| OrderEvent::Add {
+                order_id: order.id.clone(),
+                side: order.side,
+                price,
+                quantity: order.shown_quantity,
+            }))
+            .collect()
+    }
+
+    pub fn best_bid(&self) -> Option<u64> {
+        self.best_bid
+    }
+
+    pub fn best_ask(&self) -> Option<u64> {
+        self.best_ask
+    }
+
+    /// Best bid and ask with their shown quantity and order count, and the
+    /// last trade.
+    pub fn quote(&self) -> Quote {
+        let top = |levels: &BTreeMap<u64, PriceLevel>, best: Option<u64>| best.map(|price| {
+            let level = &levels[&price];
+            DepthLevel { price, quantity: level.visible_quantity(), order_count: level.len() }
+        });
+        Quote {
+            bid: top(&self.buy_book, self.best_bid),
+            ask: top(&self.sell_book, self.best_ask),
+            last_trade_price: self.last_trade_price,
+            last_trade_quantity: self.last_trade_quantity,
+        }
+    }
+
+    /// Returns the quote if it changed since the last call.
+    pub fn take_quote_change(&mut self) -> Option<Quote> {
+        let quote = self.quote();
+        if quote == self.announced_quote {
+            return None;
+        }
+        self.announced_quote = quote.clone();
+        Some(quote)
+    }
+
+    /// Returns up to `levels` price levels per side. Untriggered stop orders
+    /// are not part of the book and are not counted.
+    pub fn depth(&self, levels: usize) -> Depth {
+        let aggregate = |(&price, level): (&u64, &PriceLevel)| DepthLevel {
+            price,
+            quantity: level.visible_quantity(),
+            order_count: level.len(),
+        };
+        Depth {
+            bids: self.buy_book.iter().rev().take(levels).map(aggregate).collect(),
+            asks: self.sell_book.iter().take(levels).map(aggregate).collect(),
+        }
+    }
+
+    /// Looks up a resting or untriggered stop order by id.
+    pub fn get_order(&self, order_id: &str) -> Option<&Order> {
+        let loc = self.index.get(order_id)?;
+        self.levels(loc.side, loc.stop).get(&loc.price)?.get(loc.slot)
+    }
+
+    /// Removes a resting order from the book and returns it with its
+    /// remaining quantity. Returns `None` if no resting order has this id.
+    pub fn cancel_order(&mut self, order_id: &str) -> Option<Order> {
+        let order = self.remove_resting(order_id)?;
+        self.reports.push(ExecutionReport::new(&order, ExecType::Cancelled));
+        Some(order)
+    }
+
+    /// Changes the price and/or remaining quantity of a resting order.
+    ///
+    /// Reducing the quantity at the same price amends the order in place and
+    /// keeps its queue position. Any price change or quantity increase takes
+    /// the order out of the book and re-submits it under the same id, so it
+    /// loses time priority and trades immediately if the new price crosses.
+    /// For an untriggered stop-limit order the price is its limit price; stop
+    /// (market) orders can only change quantity.
+    pub fn amend_order(&mut self, order_id: &str, new_price: Option<u64>, new_quantity: Option<u64>) -> Result<Vec<Trade>, AmendError> {
+        if new_quantity == Some(0) {
+            return Err(AmendError::InvalidQuantity);
+        }
+
+        let resting = self.get_order(order_id).ok_or(AmendError::NotFound)?;
+        if resting.order_type == OrderType::Stop && new_price.is_some() {
+            return Err(AmendError::InvalidPrice);
+        }
+        if new_price.is_some_and(|price| !price.is_multiple_of(self.tick_size)) {
+            return Err(AmendError::InvalidPrice);
+        }
+        let target_price = new_price.or(resting.price);
+        let target_quantity = new_quantity.unwrap_or(resting.quantity);
+
+        if resting.post_only == Some(PostOnly::Reject) && !self.index[order_id].stop {
+            let amended = Order { price: target_price, ..resting.clone() };
+            if self.post_only_price(&amended).is_err() {
+                return Err(AmendError::WouldTakeLiquidity);
+            }
+        }
+
+        if target_price == resting.price && target_quantity <= resting.quantity {
+            let loc = self.index[order_id];
+            if !loc.stop {
+                self.touched.push((loc.side, loc.price));
+            }
+            let order = self.levels_mut(loc.side, loc.stop).get_mut(&loc.price).unwrap().get_mut(loc.slot).unwrap();
+            order.quantity = target_quantity;
+            let hidden = order.shown_quantity.saturating_sub(target_quantity);
+            order.shown_quantity -= hidden;
+            let report = ExecutionReport::new(order, ExecType::Replaced);
+            self.reports.push(report);
+            if hidden > 0 && !loc.stop {
+                self.order_events.push(OrderEvent::Cancel { order_id: order_id.to_string(), quantity: hidden });
+            }
+            return Ok(vec![]);
+        }
+
+        let mark = self.order_events.len();
+        let mut order = self.remove_resting(order_id).unwrap();
+        order.price = target_price;
+        order.quantity = target_quantity;
+        order.timestamp = chrono::Utc::now();
+        self.reports.push(ExecutionReport::new(&order, ExecType::Replaced));
+
+        let mut trades = self.process(order);
+
+        // Straight from one place in the book to another is a single replace
+        if let [OrderEvent::Cancel { .. }, OrderEvent::Add { price, quantity, .. }] = self.order_events[mark..] {
+            self.order_events.truncate(mark);
+            self.order_events.push(OrderEvent::Replace { order_id: order_id.to_string(), price, quantity });
+        }
+
+        self.trigger_stops(&mut trades);
+        Ok(trades)
+    }
+
+    fn validate(&self, order: &Order) -> Option<&'static str> {
+        if order.quantity == 0 {
+            return Some("quantity must be positive");
+        }
+        if matches!(order.order_type, OrderType::Limit | OrderType::StopLimit) && order.price.is_none() {
+            return Some("limit order requires a price");
+        }
+        if order.is_stop() && order.trigger_price.is_none() {
+            return Some("stop order requires a trigger price");
+        }
+        if [order.price, order.trigger_price].iter().flatten().any(|price| !price.is_multiple_of(self.tick_size)) {
+            return Some("price is not a multiple of the tick size");
+        }
+        if order.post_only.is_some() && !matches!(order.order_type, OrderType::Limit | OrderType::StopLimit) {
+            return Some("post-only requires a limit order");
+        }
+        if order.post_only.is_some() && matches!(order.time_in_force, TimeInForce::Ioc | TimeInForce::Fok) {
+            return Some("post-only orders cannot be IOC or FOK");
+        }
+        if order.display_quantity == Some(0) {
+            return Some("display quantity must be positive");
+        }
+        if let TimeInForce::Gtd(expiry) = order.time_in_force {
+            if expiry <= order.timestamp {
+                return Some("GTD expiry time has already passed");
+            }
+        }
+        None
+    }
+
+    /// How much of `order` could trade right now against the opposite side,
+    /// capped at the order's own quantity. Orders it is not allowed to trade
+    /// with under self-trade prevention do not count.
+    fn fillable_quantity(&self, order: &Order) -> u64 {
+        let levels: Box<dyn Iterator<Item = (&u64, &PriceLevel)>> = match order.side {
+            Side::Buy => Box::new(self.sell_book.iter()),
+            Side::Sell => Box::new(self.buy_book.iter().rev()),
+        };
+
+        let mut available: u64 = 0;
+        for (&price, level) in levels {
+            let crosses = match (order.side, order.price) {
+                (_, None) => true,
+                (Side::Buy, Some(limit)) => price <= limit,
+                (Side::Sell, Some(limit)) => price >= limit,
+            };
+            if !crosses || available >= order.quantity {
+                break;
+            }
+            let at_level = match order.self_trade_prevention {
+                Some(_) => level.iter()
+                    .filter(|(_, o)| o.stp_key() != order.stp_key())
+                    .fold(0, |sum: u64, (_, o)| sum.saturating_add(o.quantity)),
+                None => level.total_quantity(),
+            };
+            available = available.saturating_add(at_level);
+        }
+
+        available.min(order.quantity)
+    }
+
+    /// Parks stop orders in the trigger book and matches everything else.
+    fn process(&mut self, order: Order) -> Vec<Trade> {
+        if order.is_stop() {
+            self.hold_stop(order);
+            return vec![];
+        }
+        self.execute(order)
+    }
+
+    /// The price a post-only order may rest at without taking liquidity:
+    /// its own limit if it does not cross, one tick behind the opposite best
+    /// price if it slides, or the reason it has to be rejected. Other orders
+    /// keep their price.
+    fn post_only_price(&self, order: &Order) -> Result<Option<u64>, &'static str> {
+        let (policy, limit) = match (order.post_only, order.price) {
+            (Some(policy), Some(limit)) => (policy, limit),
+            _ => return Ok(order.price),
+        };
+
+        let crossed = match order.side {
+            Side::Buy => self.best_ask.filter(|&ask| limit >= ask),
+            Side::Sell => self.best_bid.filter(|&bid| limit <= bid),
+        };
+        let best = match crossed {
+            Some(best) => best,
+            None => return Ok(Some(limit)),
+        };
+
+        match (policy, order.side) {
+            (PostOnly::Reject, _) => Err("post-only order would take liquidity"),
+            (PostOnly::Slide, Side::Buy) => match best.checked_sub(self.tick_size) {
+                Some(price) if price > 0 => Ok(Some(price)),
+                _ => Err("post-only order has no price to slide to"),
+            },
+            (PostOnly::Slide, Side::Sell) => Ok(Some(best + self.tick_size)),
         }
     }
 
-    pub fn submit_order(&mut self, order: Order) -> Vec<Trade> {
+    fn execute(&mut self, mut order: Order) -> Vec<Trade> {
+        match self.post_only_price(&order) {
+            Ok(price) => order.price = price,
+            Err(reason) => {
+                self.reports.push(ExecutionReport::new(&order, ExecType::Cancelled).with_reason(reason));
+                return vec![];
+            }
+        }
+
+        if order.time_in_force == TimeInForce::Fok && self.fillable_quantity(&order) < order.quantity {
+            let report = ExecutionReport::new(&order, ExecType::Cancelled)
+                .with_reason("FOK order could not be fully filled");
+            self.reports.push(report);
+            return vec![];
+        }
+
         match order.side {
             Side::Buy => self.match_buy(order),
             Side::Sell => self.match_sell(order),
         }
     }
 
+    /// Activates every stop order whose trigger the last trade price has
+    /// reached, appending the resulting trades.
+    ///
+    /// Stops are activated one at a time in the order they were entered. Each
+    /// activation can move the last trade price and trigger further stops;
+    /// those are queued behind the ones already triggered, so cascades are
+    /// processed in a deterministic order.
+    fn trigger_stops(&mut self, trades: &mut Vec<Trade>) {
+        let mut pending = VecDeque::new();
+        self.collect_triggered(&mut pending);
+
+        while let Some(mut order) = pending.pop_front() {
+            order.order_type = match order.order_type {
+                OrderType::StopLimit => OrderType::Limit,
+                _ => OrderType::Market,
+            };
+            order.timestamp = chrono::Utc::now();
+            self.reports.push(ExecutionReport::new(&order, ExecType::Triggered));
+
+            trades.extend(self.execute(order));
+            self.collect_triggered(&mut pending);
+        }
+    }
+
+    /// Moves stops triggered by the last trade price out of the trigger book
+    /// and onto the back of `pending`, in entry order.
+    fn collect_triggered(&mut self, pending: &mut VecDeque<Order>) {
+        let last = match self.last_trade_price {
+            Some(price) => price,
+            None => return,
+        };
+
+        // Buy stops fire at or above their trigger, sell stops at or below
+        let mut triggered: Vec<(u64, String)> = self.buy_stops.range(..=last)
+            .chain(self.sell_stops.range(last..))
+            .flat_map(|(_, level)| level.iter().map(|(slot, o)| (slot, o.id.clone())))
+            .collect();
+        triggered.sort();
+
+        for (_, order_id) in triggered {
+            pending.push_back(self.remove_resting(&order_id).unwrap());
+        }
+    }
+
+    /// Takes a resting or stop order out of its level and the index without
+    /// reporting anything.
+    fn remove_resting(&mut self, order_id: &str) -> Option<Order> {
+        let loc = self.index.remove(order_id)?;
+        if !loc.stop {
+            self.touched.push((loc.side, loc.price));
+        }
+        let book = self.levels_mut(loc.side, loc.stop);
+
+        let level = book.get_mut(&loc.price)?;
+        let order = level.remove(loc.slot);
+        if level.is_empty() {
+            book.remove(&loc.price);
+            if !loc.stop {
+                self.level_removed(loc.side, loc.price);
+            }
+        }
+        if let Some(order) = order.as_ref().filter(|_| !loc.stop) {
+            self.order_events.push(OrderEvent::Cancel { order_id: order.id.clone(), quantity: order.shown_quantity });
+        }
+        order
+    }
+
+    fn levels(&self, side: Side, stop: bool) -> &BTreeMap<u64, PriceLevel> {
+        match (side, stop) {
+            (Side::Buy, false) => &self.buy_book,
+            (Side::Sell, false) => &self.sell_book,
+            (Side::Buy, true) => &self.buy_stops,
+            (Side::Sell, true) => &self.sell_stops,
+        }
+    }
+
+    fn levels_mut(&mut self, side: Side, stop: bool) -> &mut BTreeMap<u64, PriceLevel> {
+        match (side, stop) {
+            (Side::Buy, false) => &mut self.buy_book,
+            (Side::Sell, false) => &mut self.sell_book,
+            (Side::Buy, true) => &mut self.buy_stops,
+            (Side::Sell, true) => &mut self.sell_stops,
+        }
+    }
+
     fn match_buy(&mut self, mut order: Order) -> Vec<Trade> {
         let mut trades = vec![];
         let prices: Vec<u64> = self.sell_book.keys().cloned().collect();
@@ -41,108 +665,14 @@ impl OrderBook {
                 }
             }
 
-            let mut queue = match self.sell_book.remove(&price) {
-                Some(q) => q,
-                None => continue,
-            };
-
-            if self.mode == MatchingMode::ProRata {
-                let total_available: u64 = queue.iter().map(|o| o.quantity).sum();
-                if total_available == 0 {
-                    continue;
-                }
-            
-                let mut fills = vec![];
-                let mut total_assigned = 0;
-            
-                // First pass: floor allocation
-                for sell_order in &queue {
-                    let share = ((sell_order.quantity as f64 / total_available as f64) * order.quantity as f64).floor() as u64;
-                    fills.push(share);
-                    total_assigned += share;
-                }
-            
-                // Distribute leftover starting with largest sellers
-                let mut remaining = order.quantity - total_assigned;
-                let mut sorted_indices: Vec<_> = queue.iter().enumerate().collect();
-                sorted_indices.sort_by(|a, b| b.1.quantity.cmp(&a.1.quantity));
-            
-                for (i, _) in sorted_indices {
-                    if remaining == 0 { break; }
-                    fills[i] += 1;
-                    remaining -= 1;
-                }
-            
-                // Execute trades
-                let mut new_queue = VecDeque::new();
-                for (i, mut sell_order) in queue.into_iter().enumerate() {
-                    let trade_qty = fills[i].min(order.quantity).min(sell_order.quantity);
-            
-                    if trade_qty > 0 {
-                        trades.push(Trade {
-                            price,
-                            quantity: trade_qty,
-                            buyer: order.user_id.clone(),
-                            seller: sell_order.user_id.clone(),
-                            timestamp: chrono::Utc::now(),
-                        });
-            
-                        order.quantity -= trade_qty;
-                        sell_order.quantity -= trade_qty;
-                    }
-            
-                    if sell_order.quantity > 0 {
-                        new_queue.push_back(sell_order);
-                    }
-            
-                    if order.quantity == 0 {
-                        break;
-                    }
-                }
-            
-                if !new_queue.is_empty() {
-                    self.sell_book.insert(price, new_queue);
-                }
-            } else {
-                while let Some(mut sell_order) = queue.pop_front() {
-                    let trade_qty = order.quantity.min(sell_order.quantity);
-                    trades.push(Trade {
-                        price,
-                        quantity: trade_qty,
-                        buyer: order.user_id.clone(),
-                        seller: sell_order.user_id.clone(),
-                        timestamp: chrono::Utc::now(),
-                    });
-
-                    order.quantity -= trade_qty;
-                    sell_order.quantity -= trade_qty;
-
-                    if sell_order.quantity > 0 {
-                        queue.push_front(sell_order);
-                        break;
-                    }
-                    if order.quantity == 0 {
-                        break;
-                    }
-                }
-
-                if !queue.is_empty() {
-                    self.sell_book.insert(price, queue);
-                }
-            }
+            self.match_level(Side::Sell, price, &mut order, &mut trades);
 
             if order.quantity == 0 {
                 break;
             }
         }
 
-        if order.quantity > 0 && matches!(order.order_type, OrderType::Limit) {
-            let price = order.price.unwrap();
-            self.buy_book.entry(price)
-                .or_insert_with(VecDeque::new)
-                .push_back(order);
-        }
-
+        self.finish(order);
         trades
     }
 
@@ -157,117 +687,312 @@ impl OrderBook {
                 }
             }
 
-            let mut queue = match self.buy_book.remove(&price) {
-                Some(q) => q,
-                None => continue,
+            self.match_level(Side::Buy, price, &mut order, &mut trades);
+
+            if order.quantity == 0 {
+                break;
+            }
+        }
+
+        self.finish(order);
+        trades
+    }
+
+    /// Fills `order` against the resting orders at one price level on
+    /// `resting_side`, removing fully filled orders from the level and index.
+    ///
+    /// Only shown quantity is allocated. The level is allocated again for
+    /// whatever the incoming order has left until either side is used up:
+    /// when an iceberg's slice is used up its reserve shows a new slice at
+    /// the back of the queue, and the next pass shares out that slice too.
+    /// The caller then moves on to the next price with the exact remainder.
+    ///
+    /// Fills are applied in order up to the first one against the incoming
+    /// order's own STP group. The policy is applied to that resting order
+    /// and the level is allocated again.
+    ///
+    /// The strategy's fills are not trusted: each one is clamped to the
+    /// resting order's shown slice and to what the incoming order has left,
+    /// and fills for slots that are not at the level are dropped.
+    fn match_level(&mut self, resting_side: Side, price: u64, order: &mut Order, trades: &mut Vec<Trade>) {
+        self.touched.push((resting_side, price));
+
+        loop {
+            let book = match resting_side {
+                Side::Buy => &mut self.buy_book,
+                Side::Sell => &mut self.sell_book,
+            };
+            let level = match book.get_mut(&price) {
+                Some(level) => level,
+                None => return,
             };
 
-            if self.mode == MatchingMode::ProRata {
-                let total_available: u64 = queue.iter().map(|o| o.quantity).sum();
-                if total_available == 0 {
-                    continue;
-                }
-            
-                let mut fills = vec![];
-                let mut total_assigned = 0;
-            
-                // First pass: floor allocation
-                for buy_order in &queue {
-                    let share = ((buy_order.quantity as f64 / total_available as f64) * order.quantity as f64).floor() as u64;
-                    fills.push(share);
-                    total_assigned += share;
-                }
-            
-                // Distribute leftover starting with largest buyers
-                let mut remaining = order.quantity - total_assigned;
-                let mut sorted_indices: Vec<_> = queue.iter().enumerate().collect();
-                sorted_indices.sort_by(|a, b| b.1.quantity.cmp(&a.1.quantity));
-            
-                for (i, _) in sorted_indices {
-                    if remaining == 0 { break; }
-                    fills[i] += 1;
-                    remaining -= 1;
+            let allocation = self.strategy.allocate(&LevelView::new(level), order.quantity);
+            let self_trade = |slot: u64| {
+                order.self_trade_prevention.is_some() && level.get(slot).is_some_and(|resting| resting.stp_key() == order.stp_key())
+            };
+            let conflict = allocation.fills.iter().position(|&(slot, _)| self_trade(slot));
+            let (fills, blocked) = allocation.fills.split_at(conflict.unwrap_or(allocation.fills.len()));
+
+            // Strategies may hand out more than an order shows or the incoming
+            // order has left; only what fits will trade
+            let mut left = order.quantity;
+            let mut executed: Vec<(u64, u64)> = Vec::with_capacity(fills.len());
+            for &(slot, qty) in fills {
+                let Some(resting) = level.get(slot) else { continue };
+                let planned: u64 = executed.iter().filter(|&&(s, _)| s == slot).map(|&(_, q)| q).sum();
+                let qty = qty.min(resting.shown_quantity - planned).min(left);
+                if qty > 0 {
+                    left -= qty;
+                    executed.push((slot, qty));
                 }
-            
-                // Execute trades
-                let mut new_queue = VecDeque::new();
-                for (i, mut buy_order) in queue.into_iter().enumerate() {
-                    let trade_qty = fills[i].min(order.quantity).min(buy_order.quantity);
-            
-                    if trade_qty > 0 {
-                        trades.push(Trade {
-                            price,
-                            quantity: trade_qty,
-                            buyer: buy_order.user_id.clone(),
-                            seller: order.user_id.clone(),
-                            timestamp: chrono::Utc::now(),
-                        });
-            
-                        order.quantity -= trade_qty;
-                        buy_order.quantity -= trade_qty;
-                    }
-            
-                    if buy_order.quantity > 0 {
-                        new_queue.push_back(buy_order);
-                    }
-            
-                    if order.quantity == 0 {
-                        break;
-                    }
+            }
+
+            // Decisions for orders the STP conflict kept from trading are
+            // dropped; the rest are recorded at what actually traded
+            let mut unrecorded = executed.clone();
+            for decision in &allocation.decisions {
+                if blocked.iter().any(|&(slot, _)| slot == decision.slot) {
+                    continue;
                 }
-            
-                if !new_queue.is_empty() {
-                    self.buy_book.insert(price, new_queue);
+                let quantity = match decision.step {
+                    AllocationStep::BelowMinimum => decision.quantity,
+                    _ => unrecorded.iter_mut()
+                        .filter(|(slot, _)| *slot == decision.slot)
+                        .fold(0, |recorded, (_, left)| {
+                            let take = (decision.quantity - recorded).min(*left);
+                            *left -= take;
+                            recorded + take
+                        }),
+                };
+                if quantity == 0 {
+                    continue;
                 }
-            } else {
-                while let Some(mut buy_order) = queue.pop_front() {
-                    let trade_qty = order.quantity.min(buy_order.quantity);
-                    trades.push(Trade {
+                let Some(resting) = level.get(decision.slot) else { continue };
+                self.allocations.push(AllocationRecord {
+                    symbol: order.symbol.clone(),
+                    price,
+                    incoming_order_id: order.id.clone(),
+                    resting_order_id: resting.id.clone(),
+                    resting_user_id: resting.user_id.clone(),
+                    step: decision.step,
+                    quantity,
+                    timestamp: chrono::Utc::now(),
+                });
+            }
+            let mut allocated = 0;
+            for (slot, trade_qty) in executed {
+                let Some(resting) = level.get_mut(slot) else { continue };
+                allocated += trade_qty;
+                self.trade_sequence += 1;
+                self.last_trade_price = Some(price);
+                self.last_trade_quantity = Some(trade_qty);
+                let mut trade = make_trade(self.trade_sequence, price, trade_qty, order, resting);
+                trade.lmm_quantity = allocation.decisions.iter()
+                    .filter(|d| d.slot == slot && d.step == AllocationStep::LeadMarketMaker)
+                    .map(|d| d.quantity)
+                    .sum::<u64>()
+                    .min(trade_qty);
+                trades.push(trade);
+
+                order.record_fill(price, trade_qty);
+                resting.record_fill(price, trade_qty);
+                self.reports.push(ExecutionReport::fill(order, price, trade_qty));
+                self.reports.push(ExecutionReport::fill(resting, price, trade_qty));
+                self.order_events.push(OrderEvent::Execute {
+                    order_id: resting.id.clone(),
+                    quantity: trade_qty,
+                    trade_sequence: self.trade_sequence,
+                });
+
+                if resting.quantity == 0 {
+                    let filled = level.remove(slot).unwrap();
+                    self.index.remove(&filled.id);
+                } else if resting.needs_replenish() {
+                    let mut iceberg = level.remove(slot).unwrap();
+                    iceberg.refresh_display();
+                    self.order_events.push(OrderEvent::Add {
+                        order_id: iceberg.id.clone(),
+                        side: resting_side,
                         price,
-                        quantity: trade_qty,
-                        buyer: buy_order.user_id.clone(),
-                        seller: order.user_id.clone(),
-                        timestamp: chrono::Utc::now(),
+                        quantity: iceberg.shown_quantity,
                     });
 
-                    order.quantity -= trade_qty;
-                    buy_order.quantity -= trade_qty;
+                    let new_slot = self.next_slot;
+                    self.next_slot += 1;
+                    self.index.get_mut(&iceberg.id).unwrap().slot = new_slot;
+                    level.push_back(new_slot, iceberg);
+                }
+            }
+
+            if level.is_empty() {
+                book.remove(&price);
+                self.level_removed(resting_side, price);
+                return;
+            }
+            if order.quantity == 0 {
+                return;
+            }
+            match conflict {
+                Some(at) => self.prevent_self_trade(resting_side, price, order, allocation.fills[at].0),
+                None if allocated == 0 => return,
+                None => {}
+            }
+        }
+    }
 
-                    if buy_order.quantity > 0 {
-                        queue.push_front(buy_order);
-                        break;
-                    }
+    /// Applies the incoming order's self-trade prevention policy to the
+    /// resting order in `slot`, from its own STP group, once the allocation
+    /// reaches it. Orders ahead of it in the allocation have already traded.
+    fn prevent_self_trade(&mut self, resting_side: Side, price: u64, order: &mut Order, slot: u64) {
+        let policy = order.self_trade_prevention.unwrap();
+        let book = match resting_side {
+            Side::Buy => &mut self.buy_book,
+            Side::Sell => &mut self.sell_book,
+        };
+        let level = book.get_mut(&price).unwrap();
 
-                    if order.quantity == 0 {
-                        break;
-                    }
+        let reason = "self-trade prevention";
+        match policy {
+            SelfTradePrevention::DecrementAndCancel => {
+                let resting = level.get_mut(slot).unwrap();
+                let decrement = order.quantity.min(resting.quantity);
+                order.quantity -= decrement;
+                resting.quantity -= decrement;
+                // The reserve goes first, so the shown slice only shrinks
+                // once the reserve is gone and never needs replenishing
+                let hidden = resting.shown_quantity.saturating_sub(resting.quantity);
+                resting.shown_quantity -= hidden;
+                if hidden > 0 {
+                    self.order_events.push(OrderEvent::Cancel { order_id: resting.id.clone(), quantity: hidden });
                 }
 
-                if !queue.is_empty() {
-                    self.buy_book.insert(price, queue);
+                if resting.quantity == 0 {
+                    let cancelled = level.remove(slot).unwrap();
+                    self.index.remove(&cancelled.id);
+                    self.reports.push(ExecutionReport::new(&cancelled, ExecType::Cancelled).with_reason(reason));
+                } else {
+                    self.reports.push(ExecutionReport::new(resting, ExecType::Replaced).with_reason(reason));
                 }
-            }
 
-            if order.quantity == 0 {
-                break;
+                let exec_type = if order.quantity == 0 { ExecType::Cancelled } else { ExecType::Replaced };
+                self.reports.push(ExecutionReport::new(order, exec_type).with_reason(reason));
+            }
+            _ => {
+                if matches!(policy, SelfTradePrevention::CancelOldest | SelfTradePrevention::CancelBoth) {
+                    let cancelled = level.remove(slot).unwrap();
+                    self.index.remove(&cancelled.id);
+                    self.order_events.push(OrderEvent::Cancel { order_id: cancelled.id.clone(), quantity: cancelled.shown_quantity });
+                    self.reports.push(ExecutionReport::new(&cancelled, ExecType::Cancelled).with_reason(reason));
+                }
+                if matches!(policy, SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth) {
+                    self.reports.push(ExecutionReport::new(order, ExecType::Cancelled).with_reason(reason));
+                    order.quantity = 0;
+                }
             }
         }
 
-        if order.quantity > 0 && matches!(order.order_type, OrderType::Limit) {
-            let price = order.price.unwrap();
-            self.sell_book.entry(price)
-                .or_insert_with(VecDeque::new)
-                .push_back(order);
+        if level.is_empty() {
+            book.remove(&price);
+            self.level_removed(resting_side, price);
         }
+    }
 
-        trades
+    /// Rests whatever is left of a limit order; unfilled market and
+    /// IOC/FOK quantity is cancelled.
+    fn finish(&mut self, order: Order) {
+        if order.quantity == 0 {
+            return;
+        }
+
+        let reason = match (&order.order_type, order.time_in_force) {
+            (OrderType::Market, _) => "no liquidity for remaining market quantity",
+            (_, TimeInForce::Ioc) | (_, TimeInForce::Fok) => "unfilled immediate-or-cancel quantity",
+            _ => return self.rest(order),
+        };
+        self.reports.push(ExecutionReport::new(&order, ExecType::Cancelled).with_reason(reason));
+    }
+
+    fn rest(&mut self, mut order: Order) {
+        let price = order.price.unwrap();
+        order.refresh_display();
+        self.insert(order, price, false);
+    }
+
+    fn hold_stop(&mut self, order: Order) {
+        let trigger = order.trigger_price.unwrap();
+        self.insert(order, trigger, true);
+    }
+
+    fn insert(&mut self, order: Order, price: u64, stop: bool) {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+
+        self.index.insert(order.id.clone(), OrderLocation { side: order.side, price, slot, stop });
+        if let Some(expiry) = order.expires_at() {
+            self.expiries.insert((expiry, order.id.clone()));
+        }
+        if !stop {
+            self.level_added(order.side, price);
+            self.touched.push((order.side, price));
+            self.order_events.push(OrderEvent::Add {
+                order_id: order.id.clone(),
+                side: order.side,
+                price,
+                quantity: order.shown_quantity,
+            });
+        }
+        self.levels_mut(order.side, stop).entry(price).or_default().push_back(slot, order);
+    }
+
+    /// Keeps the best prices right after a price level is created.
+    fn level_added(&mut self, side: Side, price: u64) {
+        match side {
+            Side::Buy => self.best_bid = self.best_bid.max(Some(price)),
+            Side::Sell => self.best_ask = Some(self.best_ask.map_or(price, |ask| ask.min(price))),
+        }
+    }
+
+    /// Keeps the best prices right after a price level is removed; only
+    /// removing the best level needs a look at the next one.
+    fn level_removed(&mut self, side: Side, price: u64) {
+        match side {
+            Side::Buy if self.best_bid == Some(price) => self.best_bid = self.buy_book.keys().next_back().copied(),
+            Side::Sell if self.best_ask == Some(price) => self.best_ask = self.sell_book.keys().next().copied(),
+            _ => {}
+        }
+    }
+}
+
+fn make_trade(sequence: u64, price: u64, quantity: u64, incoming: &Order, resting: &Order) -> Trade {
+    let (buyer, seller) = match incoming.side {
+        Side::Buy => (incoming, resting),
+        Side::Sell => (resting, incoming),
+    };
+
+    Trade {
+        id: Uuid::new_v4().to_string(),
+        sequence,
+        symbol: incoming.symbol.clone(),
+        price,
+        quantity,
+        buyer: buyer.user_id.clone(),
+        seller: seller.user_id.clone(),
+        buy_order_id: buyer.id.clone(),
+        sell_order_id: seller.id.clone(),
+        aggressor_side: incoming.side,
+        lmm_quantity: 0,
+        timestamp: chrono::Utc::now(),
     }
 }
 
 #[cfg(test)]
+// The original tests check levels with `get(..).is_some()`
+#[allow(clippy::unnecessary_get_then_check)]
 mod tests {
     use super::*;
-    use crate::models::{Order, Side, OrderType};
+    use crate::allocation::{AllocationDecision, LevelAllocation, LmmAllocation, ProRataPolicy};
+    use crate::models::{Order, Side, OrderType, PostOnly, SelfTradePrevention, TimeInForce};
 
     fn make_order(user: &str, price: u64, qty: u64, side: Side) -> Order {
         Order::new(
@@ -329,15 +1054,93 @@ mod tests {
     }
 
     #[test]
-    fn test_market_order_executes_best_price() {
-        let mut book = OrderBook::new(MatchingMode::Fifo);
-        book.submit_order(make_order("s1", 99, 5, Side::Sell));
-        book.submit_order(make_order("s2", 98, 5, Side::Sell));
+    fn test_size_priority_fills_largest_seller_first() {
+        let mut book = OrderBook::new(MatchingMode::SizePriority);
+        book.submit_order(make_order("s1", 100, 10, Side::Sell));
+        book.submit_order(make_order("s2", 100, 30, Side::Sell));
 
-        let trades = book.submit_order(make_market_order("b1", 10, Side::Buy));
-        assert_eq!(trades.len(), 2);
-        assert_eq!(trades[0].price, 98);
-        assert_eq!(trades[1].price, 99);
+        let trades = book.submit_order(make_order("b1", 100, 35, Side::Buy));
+        let fills: Vec<(&str, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity)).collect();
+        assert_eq!(fills, vec![("s2", 30), ("s1", 5)]);
+    }
+
+    /// Gives every resting order the whole incoming quantity, twice, and
+    /// names a slot that does not exist.
+    struct Greedy;
+
+    impl AllocationStrategy for Greedy {
+        fn allocate(&self, level: &LevelView, quantity: u64) -> LevelAllocation {
+            let slots = level.iter().map(|(slot, _, _)| slot).chain([u64::MAX]);
+            let fills: Vec<(u64, u64)> = slots.flat_map(|slot| [(slot, quantity), (slot, quantity)]).collect();
+            let decisions = fills.iter()
+                .map(|&(slot, quantity)| AllocationDecision { slot, step: AllocationStep::Fifo, quantity })
+                .collect();
+            LevelAllocation { fills, decisions }
+        }
+    }
+
+    #[test]
+    fn test_over_allocating_strategy_is_clamped() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.strategy = Box::new(Greedy);
+        book.submit_order(make_order("s1", 100, 4, Side::Sell));
+        book.submit_order(make_order("s2", 100, 10, Side::Sell).with_display_quantity(3));
+
+        let trades = book.submit_order(make_order("b1", 100, 9, Side::Buy));
+        let fills: Vec<(&str, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity)).collect();
+        assert_eq!(fills, vec![("s1", 4), ("s2", 3), ("s2", 2)]);
+        assert_eq!(book.sell_book.get(&100).unwrap().total_quantity(), 5);
+        assert!(book.buy_book.is_empty());
+        let audited: Vec<(&str, u64)> = book.allocations.iter().map(|r| (r.resting_user_id.as_str(), r.quantity)).collect();
+        assert_eq!(audited, fills);
+    }
+
+    #[test]
+    fn test_lead_market_maker_share_is_on_the_trade() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.strategy = Box::new(LmmAllocation {
+            makers: ["mm".to_string()].into_iter().collect(),
+            percent: 50,
+            residual: Box::new(FifoAllocation),
+        });
+        book.submit_order(make_order("s1", 100, 10, Side::Sell));
+        book.submit_order(make_order("mm", 100, 30, Side::Sell));
+
+        let trades = book.submit_order(make_order("b1", 100, 20, Side::Buy));
+        let fills: Vec<(&str, u64, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity, t.lmm_quantity)).collect();
+        assert_eq!(fills, vec![("s1", 10, 0), ("mm", 10, 10)]);
+
+        let steps: Vec<AllocationStep> = book.take_allocations().iter().map(|r| r.step).collect();
+        assert_eq!(steps, vec![AllocationStep::LeadMarketMaker, AllocationStep::Fifo]);
+    }
+
+    #[test]
+    fn test_iceberg_lead_market_maker_share_is_within_each_trade() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.strategy = Box::new(LmmAllocation {
+            makers: ["mm".to_string()].into_iter().collect(),
+            percent: 50,
+            residual: Box::new(FifoAllocation),
+        });
+        book.submit_order(make_order("s1", 100, 10, Side::Sell));
+        book.submit_order(make_order("mm", 100, 30, Side::Sell).with_display_quantity(3));
+
+        // The maker's quota is 10 but it only shows 3 at a time
+        let trades = book.submit_order(make_order("b1", 100, 20, Side::Buy));
+        let fills: Vec<(&str, u64, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity, t.lmm_quantity)).collect();
+        assert_eq!(fills, vec![("s1", 10, 0), ("mm", 3, 3), ("mm", 3, 3), ("mm", 3, 2), ("mm", 1, 0)]);
+    }
+
+    #[test]
+    fn test_market_order_executes_best_price() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 99, 5, Side::Sell));
+        book.submit_order(make_order("s2", 98, 5, Side::Sell));
+
+        let trades = book.submit_order(make_market_order("b1", 10, Side::Buy));
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price, 98);
+        assert_eq!(trades[1].price, 99);
     }
 
     #[test]
@@ -398,4 +1201,972 @@ mod tests {
         assert!(trades.iter().all(|t| t.price == 100));
     }
 
+    #[test]
+    fn test_pro_rata_sweeps_levels_until_limit() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        book.submit_order(make_order("s1", 100, 7, Side::Sell));
+        book.submit_order(make_order("s2", 100, 3, Side::Sell));
+        book.submit_order(make_order("s3", 101, 4, Side::Sell));
+        book.submit_order(make_order("s4", 101, 6, Side::Sell));
+        book.submit_order(make_order("s5", 102, 10, Side::Sell));
+
+        let buy = make_order("b1", 101, 25, Side::Buy);
+        let trades = book.submit_order(buy.clone());
+
+        // Level 100 is consumed whole, then level 101, and 102 is beyond the limit
+        let fills: Vec<(&str, u64, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.price, t.quantity)).collect();
+        assert_eq!(fills, vec![("s1", 100, 7), ("s2", 100, 3), ("s3", 101, 4), ("s4", 101, 6)]);
+        assert_eq!(book.get_order(&buy.id).unwrap().quantity, 5);
+        assert!(!book.sell_book.contains_key(&100));
+        assert!(!book.sell_book.contains_key(&101));
+        assert_eq!(book.sell_book.get(&102).unwrap().total_quantity(), 10);
+    }
+
+    #[test]
+    fn test_pro_rata_sweep_reallocates_within_level() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        book.strategy = Box::new(ProRataAllocation {
+            policy: ProRataPolicy { min_allocation: 5, ..Default::default() },
+            lot_size: 1,
+        });
+        // Shares of 1 are below the minimum and the icebergs show 2 at a time,
+        // so the level can only be consumed over several allocation passes
+        book.submit_order(make_order("s1", 100, 20, Side::Sell).with_display_quantity(2));
+        book.submit_order(make_order("s2", 100, 20, Side::Sell).with_display_quantity(2));
+        book.submit_order(make_order("s3", 100, 1, Side::Sell));
+        book.submit_order(make_order("s4", 101, 10, Side::Sell));
+
+        let buy = make_market_order("b1", 46, Side::Buy);
+        let trades = book.submit_order(buy.clone());
+        let at_100: u64 = trades.iter().filter(|t| t.price == 100).map(|t| t.quantity).sum();
+        let at_101: u64 = trades.iter().filter(|t| t.price == 101).map(|t| t.quantity).sum();
+        assert_eq!((at_100, at_101), (41, 5));
+        assert!(!book.sell_book.contains_key(&100));
+        assert_eq!(book.sell_book.get(&101).unwrap().total_quantity(), 5);
+        let reports = book.take_reports();
+        let last = reports.iter().rev().find(|r| r.order_id == buy.id).unwrap();
+        assert_eq!((last.exec_type, last.cumulative_quantity), (ExecType::Fill, 46));
+    }
+
+    #[test]
+    fn test_cancel_resting_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = make_order("buyer1", 99, 10, Side::Buy);
+        book.submit_order(order.clone());
+        assert_eq!(book.get_order(&order.id).unwrap().user_id, "buyer1");
+
+        let cancelled = book.cancel_order(&order.id).unwrap();
+        assert_eq!(cancelled.quantity, 10);
+        assert!(book.buy_book.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_partially_filled_order_returns_remaining() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = make_order("seller1", 100, 10, Side::Sell);
+        book.submit_order(order.clone());
+        book.submit_order(make_order("buyer1", 100, 4, Side::Buy));
+
+        let cancelled = book.cancel_order(&order.id).unwrap();
+        assert_eq!(cancelled.quantity, 6);
+        assert!(book.sell_book.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_keeps_other_orders_at_level() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let first = make_order("s1", 100, 5, Side::Sell);
+        let second = make_order("s2", 100, 5, Side::Sell);
+        book.submit_order(first.clone());
+        book.submit_order(second.clone());
+
+        book.cancel_order(&first.id).unwrap();
+        let trades = book.submit_order(make_order("b1", 100, 5, Side::Buy));
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller, "s2");
+    }
+
+    #[test]
+    fn test_cancel_unknown_or_filled_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = make_order("s1", 100, 5, Side::Sell);
+        book.submit_order(order.clone());
+        book.submit_order(make_order("b1", 100, 5, Side::Buy));
+
+        assert!(book.get_order(&order.id).is_none());
+        assert!(book.cancel_order(&order.id).is_none());
+        assert!(book.cancel_order("does-not-exist").is_none());
+    }
+
+    #[test]
+    fn test_amend_reduce_quantity_keeps_priority() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let first = make_order("s1", 100, 10, Side::Sell);
+        book.submit_order(first.clone());
+        book.submit_order(make_order("s2", 100, 10, Side::Sell));
+
+        let trades = book.amend_order(&first.id, None, Some(4)).unwrap();
+        assert!(trades.is_empty());
+
+        let queue = book.sell_book.get(&100).unwrap();
+        assert_eq!(queue.front().unwrap().id, first.id);
+        assert_eq!(queue.front().unwrap().quantity, 4);
+    }
+
+    #[test]
+    fn test_amend_increase_quantity_loses_priority() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let first = make_order("s1", 100, 10, Side::Sell);
+        book.submit_order(first.clone());
+        book.submit_order(make_order("s2", 100, 10, Side::Sell));
+
+        book.amend_order(&first.id, None, Some(15)).unwrap();
+
+        let queue = book.sell_book.get(&100).unwrap();
+        assert_eq!(queue.front().unwrap().user_id, "s2");
+        assert_eq!(queue.back().unwrap().id, first.id);
+        assert_eq!(queue.back().unwrap().quantity, 15);
+    }
+
+    #[test]
+    fn test_amend_price_moves_level_and_loses_priority() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let first = make_order("b1", 99, 10, Side::Buy);
+        book.submit_order(first.clone());
+        book.submit_order(make_order("b2", 100, 10, Side::Buy));
+
+        book.amend_order(&first.id, Some(100), None).unwrap();
+
+        assert!(!book.buy_book.contains_key(&99));
+        let queue = book.buy_book.get(&100).unwrap();
+        assert_eq!(queue.front().unwrap().user_id, "b2");
+        assert_eq!(queue.back().unwrap().id, first.id);
+    }
+
+    #[test]
+    fn test_amend_price_that_crosses_rematches() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 101, 5, Side::Sell));
+        let bid = make_order("b1", 99, 8, Side::Buy);
+        book.submit_order(bid.clone());
+
+        let trades = book.amend_order(&bid.id, Some(101), None).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, 101);
+        assert_eq!(trades[0].quantity, 5);
+        assert!(book.sell_book.is_empty());
+        assert_eq!(book.buy_book.get(&101).unwrap().front().unwrap().quantity, 3);
+    }
+
+    #[test]
+    fn test_amend_rejects_unknown_order_and_zero_quantity() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = make_order("b1", 99, 10, Side::Buy);
+        book.submit_order(order.clone());
+
+        assert_eq!(book.amend_order("does-not-exist", Some(100), None), Err(AmendError::NotFound));
+        assert_eq!(book.amend_order(&order.id, None, Some(0)), Err(AmendError::InvalidQuantity));
+    }
+
+    #[test]
+    fn test_trades_identify_orders_aggressor_and_sequence() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let s1 = make_order("s1", 100, 5, Side::Sell);
+        let s2 = make_order("s2", 101, 5, Side::Sell);
+        book.submit_order(s1.clone());
+        book.submit_order(s2.clone());
+        let buy = make_order("b1", 101, 10, Side::Buy);
+        let trades = book.submit_order(buy.clone());
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].sequence, 1);
+        assert_eq!(trades[1].sequence, 2);
+        assert_ne!(trades[0].id, trades[1].id);
+        assert_eq!(trades[0].buy_order_id, buy.id);
+        assert_eq!(trades[0].sell_order_id, s1.id);
+        assert_eq!(trades[1].sell_order_id, s2.id);
+        assert!(trades.iter().all(|t| t.aggressor_side == Side::Buy && t.symbol == "AAPL"));
+
+        book.submit_order(make_order("b2", 100, 5, Side::Buy));
+        let sell = make_order("s3", 100, 5, Side::Sell);
+        let trades = book.submit_order(sell.clone());
+        assert_eq!(trades[0].sequence, 3);
+        assert_eq!(trades[0].sell_order_id, sell.id);
+        assert_eq!(trades[0].aggressor_side, Side::Sell);
+    }
+
+    fn exec_types(reports: &[ExecutionReport], order_id: &str) -> Vec<ExecType> {
+        reports.iter().filter(|r| r.order_id == order_id).map(|r| r.exec_type).collect()
+    }
+
+    #[test]
+    fn test_reports_for_resting_and_partially_filled_orders() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let sell = make_order("s1", 100, 10, Side::Sell);
+        book.submit_order(sell.clone());
+        assert_eq!(exec_types(&book.take_reports(), &sell.id), vec![ExecType::New]);
+
+        let buy = make_order("b1", 101, 4, Side::Buy);
+        book.submit_order(buy.clone());
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &buy.id), vec![ExecType::New, ExecType::Fill]);
+        assert_eq!(exec_types(&reports, &sell.id), vec![ExecType::PartialFill]);
+
+        let resting = reports.iter().find(|r| r.order_id == sell.id).unwrap();
+        assert_eq!(resting.last_price, 100);
+        assert_eq!(resting.last_quantity, 4);
+        assert_eq!(resting.leaves_quantity, 6);
+        assert_eq!(resting.cumulative_quantity, 4);
+        assert_eq!(resting.average_price, 100.0);
+    }
+
+    #[test]
+    fn test_reports_average_price_across_levels() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 1, Side::Sell));
+        book.submit_order(make_order("s2", 103, 1, Side::Sell));
+        book.take_reports();
+
+        let buy = make_order("b1", 103, 5, Side::Buy);
+        book.submit_order(buy.clone());
+        let reports = book.take_reports();
+        let last = reports.iter().rev().find(|r| r.order_id == buy.id).unwrap();
+        assert_eq!(last.exec_type, ExecType::PartialFill);
+        assert_eq!(last.cumulative_quantity, 2);
+        assert_eq!(last.leaves_quantity, 3);
+        assert_eq!(last.average_price, 101.5);
+    }
+
+    #[test]
+    fn test_reports_for_cancel_amend_and_market_remainder() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = make_order("b1", 99, 10, Side::Buy);
+        book.submit_order(order.clone());
+        book.amend_order(&order.id, None, Some(5)).unwrap();
+        book.amend_order(&order.id, Some(98), None).unwrap();
+        book.cancel_order(&order.id).unwrap();
+        let reports = book.take_reports();
+        assert_eq!(
+            exec_types(&reports, &order.id),
+            vec![ExecType::New, ExecType::Replaced, ExecType::Replaced, ExecType::Cancelled]
+        );
+        assert_eq!(reports[2].price, Some(98));
+        assert_eq!(reports[3].leaves_quantity, 0);
+
+        book.submit_order(make_order("s1", 100, 2, Side::Sell));
+        let market = make_market_order("b2", 5, Side::Buy);
+        book.submit_order(market.clone());
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &market.id), vec![ExecType::New, ExecType::PartialFill, ExecType::Cancelled]);
+    }
+
+    #[test]
+    fn test_invalid_orders_are_rejected() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let zero = make_order("b1", 100, 0, Side::Buy);
+        let no_price = Order::new("b2".to_string(), "AAPL".to_string(), Side::Buy, OrderType::Limit, None, 5);
+
+        assert!(book.submit_order(zero.clone()).is_empty());
+        assert!(book.submit_order(no_price.clone()).is_empty());
+        let reports = book.take_reports();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|r| r.exec_type == ExecType::Rejected && r.reason.is_some()));
+        assert!(book.buy_book.is_empty());
+    }
+
+    #[test]
+    fn test_ioc_limit_order_never_rests() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            book.submit_order(make_order("s1", 100, 3, Side::Sell));
+            let ioc = make_order("b1", 100, 5, Side::Buy).with_time_in_force(TimeInForce::Ioc);
+            let trades = book.submit_order(ioc.clone());
+
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].quantity, 3);
+            assert!(book.buy_book.is_empty());
+            assert!(book.get_order(&ioc.id).is_none());
+            let last = book.take_reports().pop().unwrap();
+            assert_eq!(last.exec_type, ExecType::Cancelled);
+        }
+    }
+
+    #[test]
+    fn test_fok_kills_without_trading_when_not_fully_fillable() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            book.submit_order(make_order("s1", 100, 3, Side::Sell));
+            book.submit_order(make_order("s2", 101, 3, Side::Sell));
+            book.submit_order(make_order("s3", 102, 3, Side::Sell));
+            book.take_reports();
+
+            let fok = make_order("b1", 101, 7, Side::Buy).with_time_in_force(TimeInForce::Fok);
+            assert!(book.submit_order(fok.clone()).is_empty());
+            assert_eq!(exec_types(&book.take_reports(), &fok.id), vec![ExecType::New, ExecType::Cancelled]);
+            assert_eq!(book.sell_book.len(), 3);
+            assert!(book.buy_book.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_fok_fills_completely_across_levels() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            book.submit_order(make_order("s1", 100, 3, Side::Sell));
+            book.submit_order(make_order("s2", 100, 2, Side::Sell));
+            book.submit_order(make_order("s3", 101, 3, Side::Sell));
+
+            let fok = make_order("b1", 101, 7, Side::Buy).with_time_in_force(TimeInForce::Fok);
+            let trades = book.submit_order(fok);
+            assert_eq!(trades.iter().map(|t| t.quantity).sum::<u64>(), 7);
+            assert_eq!(book.sell_book.get(&101).unwrap().total_quantity(), 1);
+        }
+    }
+
+    #[test]
+    fn test_fok_market_order_checks_whole_book() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("b1", 100, 4, Side::Buy));
+        let fok = make_market_order("s1", 5, Side::Sell).with_time_in_force(TimeInForce::Fok);
+        assert!(book.submit_order(fok).is_empty());
+        assert_eq!(book.buy_book.get(&100).unwrap().total_quantity(), 4);
+    }
+
+    #[test]
+    fn test_gtd_and_day_orders_expire() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let now = chrono::Utc::now();
+        let gtd = make_order("b1", 99, 5, Side::Buy)
+            .with_time_in_force(TimeInForce::Gtd(now + chrono::Duration::minutes(5)));
+        let day = make_order("b2", 98, 5, Side::Buy).with_time_in_force(TimeInForce::Day);
+        let gtc = make_order("b3", 97, 5, Side::Buy);
+        book.submit_order(gtd.clone());
+        book.submit_order(day.clone());
+        book.submit_order(gtc.clone());
+        book.take_reports();
+
+        assert!(book.expire_orders(now).is_empty());
+
+        let expired = book.expire_orders(now + chrono::Duration::minutes(10));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, gtd.id);
+
+        let expired = book.expire_orders(now + chrono::Duration::days(2));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, day.id);
+        assert!(book.get_order(&gtc.id).is_some());
+
+        let reports = book.take_reports();
+        assert!(reports.iter().all(|r| r.exec_type == ExecType::Expired && r.leaves_quantity == 0));
+    }
+
+    #[test]
+    fn test_cancelled_gtd_order_does_not_expire_later() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let now = chrono::Utc::now();
+        let gtd = make_order("b1", 99, 5, Side::Buy)
+            .with_time_in_force(TimeInForce::Gtd(now + chrono::Duration::minutes(5)));
+        book.submit_order(gtd.clone());
+        book.cancel_order(&gtd.id).unwrap();
+
+        assert!(book.expire_orders(now + chrono::Duration::minutes(10)).is_empty());
+    }
+
+    #[test]
+    fn test_gtd_in_the_past_is_rejected() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let stale = make_order("b1", 99, 5, Side::Buy)
+            .with_time_in_force(TimeInForce::Gtd(chrono::Utc::now() - chrono::Duration::minutes(1)));
+        book.submit_order(stale);
+        assert_eq!(book.take_reports()[0].exec_type, ExecType::Rejected);
+        assert!(book.buy_book.is_empty());
+    }
+
+    fn make_stop(user: &str, trigger: u64, limit: Option<u64>, qty: u64, side: Side) -> Order {
+        let order_type = if limit.is_some() { OrderType::StopLimit } else { OrderType::Stop };
+        Order::new(user.to_string(), "AAPL".to_string(), side, order_type, limit, qty)
+            .with_trigger_price(trigger)
+    }
+
+    #[test]
+    fn test_stop_order_waits_in_trigger_book_until_triggered() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 5, Side::Sell));
+        book.submit_order(make_order("s2", 103, 5, Side::Sell));
+        let stop = make_stop("b1", 100, None, 5, Side::Buy);
+        assert!(book.submit_order(stop.clone()).is_empty());
+        assert!(book.buy_stops.contains_key(&100));
+
+        // A trade at 100 reaches the buy stop's trigger and it sweeps the book
+        let trades = book.submit_order(make_order("b2", 100, 5, Side::Buy));
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[1].buy_order_id, stop.id);
+        assert_eq!(trades[1].price, 103);
+        assert!(book.buy_stops.is_empty());
+        assert!(book.get_order(&stop.id).is_none());
+
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &stop.id), vec![ExecType::New, ExecType::Triggered, ExecType::Fill]);
+    }
+
+    #[test]
+    fn test_stop_limit_rests_at_limit_after_trigger() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("b1", 100, 5, Side::Buy));
+        let stop = make_stop("s1", 100, Some(99), 3, Side::Sell);
+        book.submit_order(stop.clone());
+
+        book.submit_order(make_order("s2", 100, 5, Side::Sell));
+        assert!(book.sell_stops.is_empty());
+        assert_eq!(book.sell_book.get(&99).unwrap().front().unwrap().id, stop.id);
+    }
+
+    #[test]
+    fn test_stop_triggers_immediately_when_already_through() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 1, Side::Sell));
+        book.submit_order(make_order("b1", 100, 1, Side::Buy));
+        book.submit_order(make_order("s2", 101, 5, Side::Sell));
+
+        let trades = book.submit_order(make_stop("b2", 99, None, 5, Side::Buy));
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, 101);
+    }
+
+    #[test]
+    fn test_stop_cascade_in_one_submit() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("b1", 100, 1, Side::Buy));
+        book.submit_order(make_order("b2", 98, 1, Side::Buy));
+        book.submit_order(make_order("b3", 96, 1, Side::Buy));
+        let first = make_stop("s1", 100, None, 1, Side::Sell);
+        let second = make_stop("s2", 98, None, 1, Side::Sell);
+        book.submit_order(second.clone());
+        book.submit_order(first.clone());
+
+        // The trade at 100 fires the first stop, whose fill at 98 fires the second
+        let trades = book.submit_order(make_order("s3", 100, 1, Side::Sell));
+        let prices: Vec<u64> = trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![100, 98, 96]);
+        assert_eq!(trades[1].sell_order_id, first.id);
+        assert_eq!(trades[2].sell_order_id, second.id);
+        assert!(book.sell_stops.is_empty());
+    }
+
+    #[test]
+    fn test_stops_triggered_together_activate_in_entry_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s0", 105, 10, Side::Sell));
+        let early = make_stop("b1", 102, None, 1, Side::Buy);
+        let late = make_stop("b2", 101, None, 1, Side::Buy);
+        book.submit_order(early.clone());
+        book.submit_order(late.clone());
+
+        book.submit_order(make_order("s1", 102, 1, Side::Sell));
+        let trades = book.submit_order(make_order("b3", 102, 1, Side::Buy));
+        assert_eq!(trades.len(), 3);
+        assert_eq!(trades[1].buy_order_id, early.id);
+        assert_eq!(trades[2].buy_order_id, late.id);
+    }
+
+    #[test]
+    fn test_cancel_and_amend_untriggered_stop() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let stop = make_stop("s1", 95, Some(94), 5, Side::Sell);
+        let stop_market = make_stop("s2", 95, None, 5, Side::Sell);
+        book.submit_order(stop.clone());
+        book.submit_order(stop_market.clone());
+
+        book.amend_order(&stop.id, Some(93), None).unwrap();
+        assert_eq!(book.get_order(&stop.id).unwrap().price, Some(93));
+        assert_eq!(book.amend_order(&stop_market.id, Some(93), None), Err(AmendError::InvalidPrice));
+
+        assert_eq!(book.cancel_order(&stop.id).unwrap().quantity, 5);
+        assert_eq!(book.cancel_order(&stop_market.id).unwrap().quantity, 5);
+        assert!(book.sell_stops.is_empty());
+    }
+
+    #[test]
+    fn test_stop_without_trigger_is_rejected() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let order = Order::new("s1".to_string(), "AAPL".to_string(), Side::Sell, OrderType::Stop, None, 5);
+        book.submit_order(order);
+        assert_eq!(book.take_reports()[0].exec_type, ExecType::Rejected);
+    }
+
+    #[test]
+    fn test_iceberg_shows_only_display_quantity() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let iceberg = make_order("s1", 100, 50, Side::Sell).with_display_quantity(10);
+        book.submit_order(iceberg.clone());
+
+        let level = book.sell_book.get(&100).unwrap();
+        assert_eq!(level.visible_quantity(), 10);
+        assert_eq!(level.total_quantity(), 50);
+    }
+
+    #[test]
+    fn test_iceberg_replenishes_at_back_of_queue() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let iceberg = make_order("s1", 100, 25, Side::Sell).with_display_quantity(10);
+        book.submit_order(iceberg.clone());
+        book.submit_order(make_order("s2", 100, 5, Side::Sell));
+
+        // Takes the first slice; the replenished slice queues behind s2
+        let trades = book.submit_order(make_order("b1", 100, 12, Side::Buy));
+        let fills: Vec<(String, u64)> = trades.iter().map(|t| (t.seller.clone(), t.quantity)).collect();
+        assert_eq!(fills, vec![("s1".to_string(), 10), ("s2".to_string(), 2)]);
+
+        let level = book.sell_book.get(&100).unwrap();
+        assert_eq!(level.front().unwrap().user_id, "s2");
+        assert_eq!(level.back().unwrap().id, iceberg.id);
+        assert_eq!(level.back().unwrap().shown_quantity, 10);
+        assert_eq!(level.back().unwrap().quantity, 15);
+    }
+
+    #[test]
+    fn test_aggressor_trades_through_iceberg_reserve() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let iceberg = make_order("s1", 100, 25, Side::Sell).with_display_quantity(10);
+        book.submit_order(iceberg.clone());
+
+        let trades = book.submit_order(make_order("b1", 100, 25, Side::Buy));
+        let quantities: Vec<u64> = trades.iter().map(|t| t.quantity).collect();
+        assert_eq!(quantities, vec![10, 10, 5]);
+        assert!(book.sell_book.is_empty());
+        assert!(book.index.is_empty());
+    }
+
+    #[test]
+    fn test_pro_rata_allocates_on_shown_quantity_only() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        book.submit_order(make_order("s1", 100, 100, Side::Sell).with_display_quantity(10));
+        book.submit_order(make_order("s2", 100, 10, Side::Sell));
+
+        let trades = book.submit_order(make_order("b1", 100, 10, Side::Buy));
+        let s1: u64 = trades.iter().filter(|t| t.seller == "s1").map(|t| t.quantity).sum();
+        let s2: u64 = trades.iter().filter(|t| t.seller == "s2").map(|t| t.quantity).sum();
+        assert_eq!((s1, s2), (5, 5));
+    }
+
+    #[test]
+    fn test_iceberg_fok_counts_hidden_reserve() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 30, Side::Sell).with_display_quantity(5));
+        let fok = make_order("b1", 100, 30, Side::Buy).with_time_in_force(TimeInForce::Fok);
+        let trades = book.submit_order(fok);
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<u64>(), 30);
+    }
+
+    #[test]
+    fn test_post_only_reject_when_crossing() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            book.submit_order(make_order("s1", 100, 5, Side::Sell));
+            let order = make_order("b1", 100, 5, Side::Buy).with_post_only(PostOnly::Reject);
+            book.take_reports();
+
+            assert!(book.submit_order(order.clone()).is_empty());
+            let reports = book.take_reports();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].exec_type, ExecType::Rejected);
+            assert_eq!(reports[0].reason.as_deref(), Some("post-only order would take liquidity"));
+            assert!(book.buy_book.is_empty());
+            assert_eq!(book.sell_book.get(&100).unwrap().total_quantity(), 5);
+        }
+    }
+
+    #[test]
+    fn test_off_tick_prices_are_rejected() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.tick_size = 5;
+
+        let off_tick = make_order("b1", 102, 5, Side::Buy);
+        book.submit_order(off_tick.clone());
+        assert!(book.get_order(&off_tick.id).is_none());
+        let reports = book.take_reports();
+        assert_eq!(reports[0].exec_type, ExecType::Rejected);
+        assert_eq!(reports[0].reason.as_deref(), Some("price is not a multiple of the tick size"));
+
+        let stop = make_stop("b1", 103, None, 5, Side::Buy);
+        book.submit_order(stop.clone());
+        assert!(book.get_order(&stop.id).is_none());
+
+        let on_tick = make_order("b1", 100, 5, Side::Buy);
+        book.submit_order(on_tick.clone());
+        assert_eq!(book.amend_order(&on_tick.id, Some(97), None), Err(AmendError::InvalidPrice));
+        assert!(book.amend_order(&on_tick.id, Some(95), None).is_ok());
+    }
+
+    #[test]
+    fn test_post_only_slide_reprices_one_tick_away() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            book.submit_order(make_order("s1", 100, 5, Side::Sell));
+            book.submit_order(make_order("b1", 95, 5, Side::Buy));
+
+            let buy = make_order("b2", 102, 5, Side::Buy).with_post_only(PostOnly::Slide);
+            assert!(book.submit_order(buy.clone()).is_empty());
+            assert_eq!(book.get_order(&buy.id).unwrap().price, Some(99));
+
+            let sell = make_order("s2", 90, 5, Side::Sell).with_post_only(PostOnly::Slide);
+            assert!(book.submit_order(sell.clone()).is_empty());
+            assert_eq!(book.get_order(&sell.id).unwrap().price, Some(100));
+        }
+    }
+
+    #[test]
+    fn test_post_only_rests_when_not_crossing() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 5, Side::Sell));
+        let order = make_order("b1", 99, 5, Side::Buy).with_post_only(PostOnly::Reject);
+        book.submit_order(order.clone());
+        assert_eq!(book.get_order(&order.id).unwrap().price, Some(99));
+    }
+
+    #[test]
+    fn test_post_only_amend_that_would_cross_is_refused() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("s1", 100, 5, Side::Sell));
+        let order = make_order("b1", 99, 5, Side::Buy).with_post_only(PostOnly::Reject);
+        book.submit_order(order.clone());
+
+        assert_eq!(book.amend_order(&order.id, Some(100), None), Err(AmendError::WouldTakeLiquidity));
+        assert_eq!(book.get_order(&order.id).unwrap().price, Some(99));
+    }
+
+    #[test]
+    fn test_post_only_needs_resting_limit_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_market_order("b1", 5, Side::Buy).with_post_only(PostOnly::Reject));
+        book.submit_order(make_order("b2", 99, 5, Side::Buy)
+            .with_post_only(PostOnly::Slide)
+            .with_time_in_force(TimeInForce::Ioc));
+        let reports = book.take_reports();
+        assert!(reports.iter().all(|r| r.exec_type == ExecType::Rejected));
+        assert_eq!(reports.len(), 2);
+    }
+
+    fn with_stp(order: Order, policy: SelfTradePrevention) -> Order {
+        order.with_self_trade_prevention(policy)
+    }
+
+    #[test]
+    fn test_self_trades_are_allowed_without_a_policy() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("u1", 100, 5, Side::Sell));
+        let trades = book.submit_order(make_order("u1", 100, 5, Side::Buy));
+        assert_eq!(trades.len(), 1);
+    }
+
+    #[test]
+    fn test_stp_cancel_newest() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            let resting = make_order("u1", 100, 5, Side::Sell);
+            book.submit_order(resting.clone());
+            let incoming = with_stp(make_order("u1", 100, 5, Side::Buy), SelfTradePrevention::CancelNewest);
+            book.take_reports();
+
+            assert!(book.submit_order(incoming.clone()).is_empty());
+            assert!(book.get_order(&resting.id).is_some());
+            assert!(book.buy_book.is_empty());
+            let reports = book.take_reports();
+            assert_eq!(exec_types(&reports, &incoming.id), vec![ExecType::New, ExecType::Cancelled]);
+            assert_eq!(reports[1].reason.as_deref(), Some("self-trade prevention"));
+        }
+    }
+
+    #[test]
+    fn test_stp_waits_until_the_allocation_reaches_own_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("u2", 100, 5, Side::Sell));
+        let own = make_order("u1", 100, 5, Side::Sell);
+        book.submit_order(own.clone());
+
+        // Fills entirely ahead of its own order
+        let incoming = with_stp(make_order("u1", 100, 3, Side::Buy), SelfTradePrevention::CancelNewest);
+        let trades = book.submit_order(incoming);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller, "u2");
+
+        // Trades what is ahead, then is cancelled on reaching its own order
+        let incoming = with_stp(make_order("u1", 100, 6, Side::Buy), SelfTradePrevention::CancelNewest);
+        book.take_reports();
+        let trades = book.submit_order(incoming.clone());
+        assert_eq!(trades.iter().map(|t| t.quantity).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(book.get_order(&own.id).unwrap().quantity, 5);
+        assert!(book.buy_book.is_empty());
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &incoming.id), vec![ExecType::New, ExecType::PartialFill, ExecType::Cancelled]);
+    }
+
+    #[test]
+    fn test_stp_cancel_oldest_keeps_matching() {
+        for mode in [MatchingMode::Fifo, MatchingMode::ProRata] {
+            let mut book = OrderBook::new(mode);
+            let own = make_order("u1", 100, 5, Side::Sell);
+            book.submit_order(own.clone());
+            book.submit_order(make_order("u2", 100, 5, Side::Sell));
+            let incoming = with_stp(make_order("u1", 100, 5, Side::Buy), SelfTradePrevention::CancelOldest);
+            book.take_reports();
+
+            let trades = book.submit_order(incoming.clone());
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].seller, "u2");
+            assert!(book.get_order(&own.id).is_none());
+            assert_eq!(exec_types(&book.take_reports(), &own.id), vec![ExecType::Cancelled]);
+        }
+    }
+
+    #[test]
+    fn test_stp_cancel_both() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let own = make_order("u1", 100, 5, Side::Sell);
+        book.submit_order(own.clone());
+        let incoming = with_stp(make_order("u1", 101, 5, Side::Buy), SelfTradePrevention::CancelBoth);
+
+        assert!(book.submit_order(incoming.clone()).is_empty());
+        assert!(book.sell_book.is_empty());
+        assert!(book.buy_book.is_empty());
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &own.id), vec![ExecType::New, ExecType::Cancelled]);
+        assert_eq!(exec_types(&reports, &incoming.id), vec![ExecType::New, ExecType::Cancelled]);
+    }
+
+    #[test]
+    fn test_stp_decrement_and_cancel() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let own = make_order("u1", 100, 3, Side::Sell);
+        book.submit_order(own.clone());
+        book.submit_order(make_order("u2", 100, 5, Side::Sell));
+        let incoming = with_stp(make_order("u1", 100, 5, Side::Buy), SelfTradePrevention::DecrementAndCancel);
+
+        // 3 is decremented from both without trading, the other 2 trade with u2
+        let trades = book.submit_order(incoming.clone());
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller, "u2");
+        assert_eq!(trades[0].quantity, 2);
+        assert!(book.get_order(&own.id).is_none());
+
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &own.id), vec![ExecType::New, ExecType::Cancelled]);
+        assert_eq!(exec_types(&reports, &incoming.id), vec![ExecType::New, ExecType::Replaced, ExecType::Fill]);
+    }
+
+    #[test]
+    fn test_stp_decrement_cancels_smaller_incoming() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let own = make_order("u1", 100, 10, Side::Sell);
+        book.submit_order(own.clone());
+        let incoming = with_stp(make_order("u1", 100, 4, Side::Buy), SelfTradePrevention::DecrementAndCancel);
+
+        assert!(book.submit_order(incoming.clone()).is_empty());
+        assert_eq!(book.get_order(&own.id).unwrap().quantity, 6);
+        assert!(book.buy_book.is_empty());
+        let reports = book.take_reports();
+        assert_eq!(exec_types(&reports, &own.id), vec![ExecType::New, ExecType::Replaced]);
+        assert_eq!(exec_types(&reports, &incoming.id), vec![ExecType::New, ExecType::Cancelled]);
+    }
+
+    #[test]
+    fn test_stp_group_spans_users() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let resting = make_order("trader_a", 100, 5, Side::Sell)
+            .with_stp_group("firm1".to_string());
+        book.submit_order(resting);
+        let incoming = make_order("trader_b", 100, 5, Side::Buy)
+            .with_self_trade_prevention(SelfTradePrevention::CancelNewest)
+            .with_stp_group("firm1".to_string());
+        assert!(book.submit_order(incoming).is_empty());
+    }
+
+    #[test]
+    fn test_stp_fok_ignores_own_liquidity() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("u1", 100, 5, Side::Sell));
+        book.submit_order(make_order("u2", 100, 5, Side::Sell));
+        let fok = with_stp(make_order("u1", 100, 10, Side::Buy), SelfTradePrevention::CancelOldest)
+            .with_time_in_force(TimeInForce::Fok);
+        assert!(book.submit_order(fok).is_empty());
+        assert_eq!(book.sell_book.get(&100).unwrap().total_quantity(), 10);
+    }
+
+    #[test]
+    fn test_index_tracks_resting_orders_through_fifo_fills() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let s1 = make_order("s1", 100, 5, Side::Sell);
+        let s2 = make_order("s2", 100, 5, Side::Sell);
+        book.submit_order(s1.clone());
+        book.submit_order(s2.clone());
+        book.submit_order(make_order("b1", 100, 7, Side::Buy));
+
+        assert!(book.get_order(&s1.id).is_none());
+        assert_eq!(book.get_order(&s2.id).unwrap().quantity, 3);
+        assert_eq!(book.index.len(), 1);
+    }
+
+    #[test]
+    fn test_pro_rata_policy_and_allocation_audit() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        book.strategy = Box::new(ProRataAllocation {
+            policy: ProRataPolicy { top_order: true, top_order_max: Some(4), ..Default::default() },
+            lot_size: 1,
+        });
+        let top = make_order("s1", 100, 10, Side::Sell);
+        book.submit_order(top.clone());
+        book.submit_order(make_order("s2", 100, 10, Side::Sell));
+
+        let incoming = make_order("b1", 100, 10, Side::Buy);
+        let trades = book.submit_order(incoming.clone());
+        let fills: Vec<(&str, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity)).collect();
+        assert_eq!(fills, vec![("s1", 6), ("s2", 4)]);
+
+        let records = book.take_allocations();
+        assert!(records.iter().all(|r| r.incoming_order_id == incoming.id && r.price == 100));
+        assert_eq!(records[0].step, AllocationStep::TopOrder);
+        assert_eq!(records[0].resting_order_id, top.id);
+        assert_eq!(records[0].quantity, 4);
+        assert_eq!(records.iter().map(|r| r.quantity).sum::<u64>(), 10);
+        assert!(book.take_allocations().is_empty());
+    }
+
+    #[test]
+    fn test_shares_below_the_minimum_are_audited() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        book.strategy = Box::new(ProRataAllocation {
+            policy: ProRataPolicy { min_allocation: 3, ..Default::default() },
+            lot_size: 1,
+        });
+        let small = make_order("s1", 100, 10, Side::Sell);
+        book.submit_order(small.clone());
+        book.submit_order(make_order("s2", 100, 45, Side::Sell));
+        book.submit_order(make_order("s3", 100, 45, Side::Sell));
+
+        // 1 / 4.5 / 4.5: the oldest order's share is below the minimum and
+        // the leftover goes to the larger remainders
+        let trades = book.submit_order(make_order("b1", 100, 10, Side::Buy));
+        let fills: Vec<(&str, u64)> = trades.iter().map(|t| (t.seller.as_str(), t.quantity)).collect();
+        assert_eq!(fills, vec![("s2", 5), ("s3", 5)]);
+
+        let records = book.take_allocations();
+        let below: Vec<(&str, u64)> = records.iter()
+            .filter(|r| r.step == AllocationStep::BelowMinimum)
+            .map(|r| (r.resting_order_id.as_str(), r.quantity))
+            .collect();
+        assert_eq!(below, vec![(small.id.as_str(), 1)]);
+    }
+
+    #[test]
+    fn test_index_tracks_resting_orders_through_pro_rata_fills() {
+        let mut book = OrderBook::new(MatchingMode::ProRata);
+        let s1 = make_order("s1", 100, 10, Side::Sell);
+        let s2 = make_order("s2", 100, 30, Side::Sell);
+        book.submit_order(s1.clone());
+        book.submit_order(s2.clone());
+        book.submit_order(make_order("b1", 100, 20, Side::Buy));
+
+        assert_eq!(book.get_order(&s1.id).unwrap().quantity, 5);
+        assert_eq!(book.get_order(&s2.id).unwrap().quantity, 15);
+
+        book.submit_order(make_order("b2", 100, 20, Side::Buy));
+        assert!(book.sell_book.is_empty());
+        assert!(book.index.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_from_middle_of_level_preserves_order() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let orders: Vec<Order> = (0..5).map(|i| make_order(&format!("s{}", i), 100, 1, Side::Sell)).collect();
+        for order in &orders {
+            book.submit_order(order.clone());
+        }
+
+        book.cancel_order(&orders[2].id).unwrap();
+        let sellers: Vec<String> = book.submit_order(make_order("b1", 100, 4, Side::Buy))
+            .into_iter()
+            .map(|t| t.seller)
+            .collect();
+        assert_eq!(sellers, vec!["s0", "s1", "s3", "s4"]);
+        assert!(book.index.is_empty());
+    }
+
+    #[test]
+    fn test_depth_aggregates_best_levels_first() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        book.submit_order(make_order("b1", 98, 5, Side::Buy));
+        book.submit_order(make_order("b2", 99, 10, Side::Buy));
+        book.submit_order(make_order("b3", 99, 7, Side::Buy));
+        book.submit_order(make_order("b4", 97, 1, Side::Buy));
+        book.submit_order(make_order("s1", 101, 50, Side::Sell).with_display_quantity(10));
+        book.submit_order(make_stop("s2", 95, None, 3, Side::Sell));
+
+        let depth = book.depth(2);
+        assert_eq!(depth.bids, vec![
+            DepthLevel { price: 99, quantity: 17, order_count: 2 },
+            DepthLevel { price: 98, quantity: 5, order_count: 1 },
+        ]);
+        assert_eq!(depth.asks, vec![DepthLevel { price: 101, quantity: 10, order_count: 1 }]);
+        assert_eq!(book.depth(10).bids.len(), 3);
+        assert_eq!(book.depth(0), Depth::default());
+    }
+
+    #[test]
+    fn test_quote_tracks_best_prices_and_last_trade() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        assert_eq!(book.take_quote_change(), None);
+
+        let b1 = make_order("b1", 98, 5, Side::Buy);
+        book.submit_order(b1.clone());
+        book.submit_order(make_order("b2", 99, 4, Side::Buy));
+        book.submit_order(make_order("s1", 101, 10, Side::Sell));
+        book.submit_order(make_order("s2", 102, 10, Side::Sell));
+        let quote = book.take_quote_change().unwrap();
+        assert_eq!(quote.bid, Some(DepthLevel { price: 99, quantity: 4, order_count: 1 }));
+        assert_eq!(quote.ask, Some(DepthLevel { price: 101, quantity: 10, order_count: 1 }));
+        assert_eq!(quote.spread(), Some(2));
+        assert_eq!(quote.last_trade_price, None);
+        assert_eq!(book.take_quote_change(), None);
+
+        // Sweeping the best ask and best bid moves both sides
+        book.submit_order(make_order("b3", 101, 10, Side::Buy));
+        book.submit_order(make_order("s3", 99, 4, Side::Sell));
+        assert_eq!((book.best_bid(), book.best_ask()), (Some(98), Some(102)));
+        let quote = book.take_quote_change().unwrap();
+        assert_eq!((quote.last_trade_price, quote.last_trade_quantity), (Some(99), Some(4)));
+
+        book.cancel_order(&b1.id);
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.quote().spread(), None);
+    }
+
+    #[test]
+    fn test_level_updates_follow_the_book() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let update = |side, action, price, quantity, order_count| LevelUpdate {
+            side,
+            action,
+            level: DepthLevel { price, quantity, order_count },
+        };
+
+        book.submit_order(make_order("b1", 99, 10, Side::Buy));
+        assert_eq!(book.take_level_updates(), vec![update(Side::Buy, LevelAction::Add, 99, 10, 1)]);
+        let b2 = make_order("b2", 99, 5, Side::Buy);
+        book.submit_order(b2.clone());
+        assert_eq!(book.take_level_updates(), vec![update(Side::Buy, LevelAction::Change, 99, 15, 2)]);
+
+        book.submit_order(make_order("s1", 99, 12, Side::Sell));
+        book.submit_order(make_stop("s2", 90, None, 5, Side::Sell));
+        assert_eq!(book.take_level_updates(), vec![update(Side::Buy, LevelAction::Change, 99, 3, 1)]);
+
+        book.amend_order(&b2.id, None, Some(2)).unwrap();
+        book.submit_order(make_order("s3", 101, 4, Side::Sell));
+        book.cancel_order(&b2.id);
+        // Changes between two calls are netted per level
+        assert_eq!(book.take_level_updates(), vec![
+            update(Side::Buy, LevelAction::Delete, 99, 0, 0),
+            update(Side::Sell, LevelAction::Add, 101, 4, 1),
+        ]);
+        assert!(book.take_level_updates().is_empty());
+    }
 }
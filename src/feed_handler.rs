@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::engine::{Depth, DepthLevel, OrderBook, OrderEvent};
+use crate::market_data::Sequenced;
+use crate::models::Side;
+
+/// A displayed order as the feed describes it.
+#[derive(Debug, Clone)]
+struct ReplicaOrder {
+    id: String,
+    quantity: u64,
+}
+
+/// Reference consumer of the order-by-order feed: rebuilds one symbol's
+/// book from a subscription's snapshot and the updates that follow it.
+///
+/// Every update must arrive in sequence and fit the book as rebuilt so far.
+/// After an error the replica can no longer be trusted and should be
+/// rebuilt from a fresh subscription.
+#[derive(Debug, Default)]
+pub struct BookReplica {
+    bids: BTreeMap<u64, VecDeque<ReplicaOrder>>,
+    asks: BTreeMap<u64, VecDeque<ReplicaOrder>>,
+    /// Side and price of every displayed order.
+    index: HashMap<String, (Side, u64)>,
+    sequence: u64,
+}
+
+impl BookReplica {
+    /// Builds the book from the `Add` events of a snapshot taken at
+    /// `sequence`.
+    pub fn from_snapshot(sequence: u64, snapshot: &[OrderEvent]) -> Result<Self, String> {
+        let mut replica = Self { sequence, ..Default::default() };
+        for event in snapshot {
+            replica.apply_event(event)?;
+        }
+        Ok(replica)
+    }
+
+    /// Sequence number of the last update applied.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Applies the next update of the feed.
+    pub fn apply(&mut self, update: &Sequenced<OrderEvent>) -> Result<(), String> {
+        if update.sequence != self.sequence + 1 {
+            return Err(format!("expected sequence {}, got {}", self.sequence + 1, update.sequence));
+        }
+        self.apply_event(&update.event)?;
+        self.sequence = update.sequence;
+        Ok(())
+    }
+
+    fn apply_event(&mut self, event: &OrderEvent) -> Result<(), String> {
+        match event {
+            OrderEvent::Add { order_id, side, price, quantity } => self.add(order_id, *side, *price, *quantity),
+            OrderEvent::Execute { order_id, quantity, .. } | OrderEvent::Cancel { order_id, quantity } => {
+                self.reduce(order_id, *quantity)
+            }
+            OrderEvent::Replace { order_id, price, quantity } => {
+                let (side, _) = self.remove(order_id)?;
+                self.add(order_id, side, *price, *quantity)
+            }
+        }
+    }
+
+    fn add(&mut self, order_id: &str, side: Side, price: u64, quantity: u64) -> Result<(), String> {
+        if quantity == 0 {
+            return Err(format!("order {} added with nothing displayed", order_id));
+        }
+        if self.index.contains_key(order_id) {
+            return Err(format!("order {} is already in the book", order_id));
+        }
+        self.index.insert(order_id.to_string(), (side, price));
+        self.levels_mut(side).entry(price).or_default().push_back(ReplicaOrder { id: order_id.to_string(), quantity });
+        Ok(())
+    }
+
+    /// Takes `quantity` off a displayed order and drops the order once
+    /// nothing is left.
+    fn reduce(&mut self, order_id: &str, quantity: u64) -> Result<(), String> {
+        let &(side, price) = self.index.get(order_id).ok_or_else(|| format!("order {} is not in the book", order_id))?;
+        let level = self.levels_mut(side).get_mut(&price).unwrap();
+        let order = level.iter_mut().find(|o| o.id == order_id).unwrap();
+        if quantity > order.quantity {
+            return Err(format!("order {} shows {}, cannot take off {}", order_id, order.quantity, quantity));
+        }
+        order.quantity -= quantity;
+        if order.quantity == 0 {
+            self.remove(order_id)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, order_id: &str) -> Result<(Side, u64), String> {
+        let (side, price) = self.index.remove(order_id).ok_or_else(|| format!("order {} is not in the book", order_id))?;
+        let levels = self.levels_mut(side);
+        let level = levels.get_mut(&price).unwrap();
+        level.retain(|o| o.id != order_id);
+        if level.is_empty() {
+            levels.remove(&price);
+        }
+        Ok((side, price))
+    }
+
+    fn levels_mut(&mut self, side: Side) -> &mut BTreeMap<u64, VecDeque<ReplicaOrder>> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    /// Up to `levels` aggregated price levels per side, the same way
+    /// `OrderBook::depth` reports them.
+    pub fn depth(&self, levels: usize) -> Depth {
+        let aggregate = |(&price, orders): (&u64, &VecDeque<ReplicaOrder>)| DepthLevel {
+            price,
+            quantity: orders.iter().fold(0, |sum, o| sum.saturating_add(o.quantity)),
+            order_count: orders.len(),
+        };
+        Depth {
+            bids: self.bids.iter().rev().take(levels).map(aggregate).collect(),
+            asks: self.asks.iter().take(levels).map(aggregate).collect(),
+        }
+    }
+
+    /// Every displayed order as `Add` events, in the same order as
+    /// `OrderBook::order_snapshot`.
+    pub fn orders(&self) -> Vec<OrderEvent> {
+        let bids = self.bids.iter().rev().map(|level| (Side::Buy, level));
+        let asks = self.asks.iter().map(|level| (Side::Sell, level));
+        bids.chain(asks)
+            .flat_map(|(side, (&price, orders))| orders.iter().map(move |o| OrderEvent::Add {
+                order_id: o.id.clone(),
+                side,
+                price,
+                quantity: o.quantity,
+            }))
+            .collect()
+    }
+
+    /// Checks the replica against the book the feed came from, level by
+    /// level and order by order.
+    pub fn check(&self, book: &OrderBook) -> Result<(), String> {
+        if self.depth(usize::MAX) != book.depth(usize::MAX) {
+            return Err("depth differs from the book".to_string());
+        }
+        if self.orders() != book.order_snapshot() {
+            return Err("order queues differ from the book".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::engine::MatchingMode;
+    use crate::models::{Order, OrderType, SelfTradePrevention};
+
+    fn limit(user: &str, side: Side, price: u64, qty: u64) -> Order {
+        Order::new(user.to_string(), "AAPL".to_string(), side, OrderType::Limit, Some(price), qty)
+    }
+
+    /// Feeds the book's pending order events to the replica.
+    fn sync(replica: &mut BookReplica, book: &mut OrderBook) {
+        for event in book.take_order_events() {
+            let update = Sequenced { sequence: replica.sequence() + 1, event };
+            replica.apply(&update).unwrap();
+        }
+        replica.check(book).unwrap();
+    }
+
+    #[test]
+    fn test_replica_follows_every_event_type() {
+        let mut book = OrderBook::new(MatchingMode::Fifo);
+        let mut replica = BookReplica::default();
+
+        let iceberg = limit("s1", Side::Sell, 101, 30).with_display_quantity(10);
+        let plain = limit("s2", Side::Sell, 101, 8);
+        let bid = limit("b1", Side::Buy, 99, 5);
+        for order in [iceberg.clone(), plain.clone(), bid.clone()] {
+            book.submit_order(order);
+        }
+        sync(&mut replica, &mut book);
+
+        // Uses up the iceberg's slice, which is shown again behind s2
+        book.submit_order(limit("b2", Side::Buy, 101, 12));
+        let events = book.take_order_events();
+        assert!(matches!(&events[..], [
+            OrderEvent::Execute { quantity: 10, .. },
+            OrderEvent::Add { quantity: 10, .. },
+            OrderEvent::Execute { quantity: 2, .. },
+        ]));
+        for event in events {
+            replica.apply(&Sequenced { sequence: replica.sequence() + 1, event }).unwrap();
+        }
+        replica.check(&book).unwrap();
+
+        book.amend_order(&bid.id, Some(98), None).unwrap();
+        assert!(matches!(&book.order_snapshot()[..], [OrderEvent::Add { price: 98, .. }, ..]));
+        let events = book.take_order_events();
+        assert_eq!(events, vec![OrderEvent::Replace { order_id: bid.id.clone(), price: 98, quantity: 5 }]);
+        for event in events {
+            replica.apply(&Sequenced { sequence: replica.sequence() + 1, event }).unwrap();
+        }
+
+        book.amend_order(&plain.id, None, Some(4)).unwrap();
+        let stp = limit("s1", Side::Buy, 101, 3).with_self_trade_prevention(SelfTradePrevention::DecrementAndCancel);
+        book.submit_order(stp);
+        book.cancel_order(&bid.id);
+        sync(&mut replica, &mut book);
+    }
+
+    #[test]
+    fn test_replica_rejects_gaps_and_unknown_orders() {
+        let mut replica = BookReplica::from_snapshot(5, &[]).unwrap();
+        let cancel = OrderEvent::Cancel { order_id: "x".to_string(), quantity: 1 };
+        assert!(replica.apply(&Sequenced { sequence: 7, event: cancel.clone() }).is_err());
+        assert!(replica.apply(&Sequenced { sequence: 6, event: cancel }).is_err());
+        assert_eq!(replica.sequence(), 5);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Action {
+        Submit { buy: bool, price: u64, qty: u64, display: Option<u64> },
+        Cancel(usize),
+        Amend { pick: usize, price: Option<u64>, qty: Option<u64> },
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            (any::<bool>(), 95..106u64, 1..30u64, prop::option::of(1..10u64))
+                .prop_map(|(buy, price, qty, display)| Action::Submit { buy, price, qty, display }),
+            any::<usize>().prop_map(Action::Cancel),
+            (any::<usize>(), prop::option::of(95..106u64), prop::option::of(1..30u64))
+                .prop_map(|(pick, price, qty)| Action::Amend { pick, price, qty }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_replica_matches_the_book(
+            actions in prop::collection::vec(action(), 1..80),
+            pro_rata in any::<bool>(),
+            join_at in 0..80usize,
+        ) {
+            let mode = if pro_rata { MatchingMode::ProRata } else { MatchingMode::Fifo };
+            let mut book = OrderBook::new(mode);
+            let mut replica = BookReplica::default();
+            let mut late: Option<BookReplica> = None;
+            let mut ids: Vec<String> = Vec::new();
+
+            for (step, action) in actions.into_iter().enumerate() {
+                if step == join_at {
+                    let snapshot = book.order_snapshot();
+                    late = Some(BookReplica::from_snapshot(replica.sequence(), &snapshot).unwrap());
+                }
+                match action {
+                    Action::Submit { buy, price, qty, display } => {
+                        let side = if buy { Side::Buy } else { Side::Sell };
+                        let mut order = limit(&format!("u{}", step % 3), side, price, qty);
+                        if let Some(display) = display {
+                            order = order.with_display_quantity(display);
+                        }
+                        ids.push(order.id.clone());
+                        book.submit_order(order);
+                    }
+                    Action::Cancel(pick) if !ids.is_empty() => {
+                        book.cancel_order(&ids[pick % ids.len()]);
+                    }
+                    Action::Amend { pick, price, qty } if !ids.is_empty() => {
+                        let _ = book.amend_order(&ids[pick % ids.len()], price, qty);
+                    }
+                    _ => {}
+                }
+
+                for event in book.take_order_events() {
+                    let update = Sequenced { sequence: replica.sequence() + 1, event };
+                    prop_assert_eq!(replica.apply(&update), Ok(()));
+                    if let Some(late) = late.as_mut() {
+                        prop_assert_eq!(late.apply(&update), Ok(()));
+                    }
+                }
+                prop_assert_eq!(replica.check(&book), Ok(()));
+                let top = replica.depth(1);
+                prop_assert_eq!(book.best_bid(), top.bids.first().map(|l| l.price));
+                prop_assert_eq!(book.best_ask(), top.asks.first().map(|l| l.price));
+                if let Some(late) = &late {
+                    prop_assert_eq!(late.check(&book), Ok(()));
+                }
+            }
+        }
+    }
+}
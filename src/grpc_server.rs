@@ -1,9 +1,13 @@
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
-use crate::engine::OrderBook;
-use crate::models::{Order, Side, OrderType};
-use crate::redis_writer::RedisWriter;
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use crate::book_manager::{BookManager, BookOutcome, BookStopped};
+use crate::engine::{AmendError, CancelError, Depth, DepthLevel, LevelUpdate, MatchingMode, OrderEvent, Quote};
+use crate::instrument::{normalize_symbol, Instrument, TradingStatus};
+use crate::market_data::{MarketDataEvent, MarketDataUpdate, Sequenced};
+use crate::models::{Order, Side, OrderType, PostOnly, SelfTradePrevention, TimeInForce};
+use crate::price::PriceFormat;
 
 // Include generated gRPC code
 pub mod order {
@@ -11,17 +15,345 @@ pub mod order {
 }
 
 use order::order_matching_server::{OrderMatching, OrderMatchingServer};
-use order::{OrderRequest, SubmitResponse, Trade};
+use order::{OrderRequest, SubmitResponse, Trade, ExecutionReport, CancelRequest, CancelResponse, AmendRequest, AmendResponse};
+use order::{GetInstrumentRequest, ListInstrumentsRequest, ListInstrumentsResponse, SetMatchingModeRequest, SetTradingStatusRequest};
+use order::{BookLevel, GetQuoteRequest, OrderBookRequest, OrderBookResponse};
+use order::{market_data_message::Payload, MarketDataMessage, MarketDataRequest, TradePrint};
+use order::{order_feed_message, OrderFeedMessage, OrderSnapshot};
+use order::{session_message::Reply, session_request::Action, SessionMessage, SessionReject, SessionRequest};
+
+/// Levels per side returned by `GetOrderBook` when the request asks for 0.
+const DEFAULT_DEPTH: usize = 10;
+
+/// Messages buffered per market data subscriber on the way to the client.
+const MARKET_DATA_STREAM_BUFFER: usize = 256;
+
+/// How many messages an order session may have queued before it stops
+/// reading requests.
+const SESSION_BUFFER: usize = 1024;
+
+/// Parses an optional decimal price field; an empty string means unset.
+fn parse_price(format: PriceFormat, text: &str, field: &str) -> Result<Option<u64>, String> {
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    format.parse(text)
+        .map(Some)
+        .map_err(|reason| format!("Invalid {}: {}", field, reason))
+}
+
+fn to_proto_trade(t: crate::models::Trade, format: PriceFormat) -> Trade {
+    Trade {
+        price: format.format(t.price),
+        quantity: t.quantity,
+        buyer: t.buyer,
+        seller: t.seller,
+        timestamp: t.timestamp.to_rfc3339(),
+        symbol: t.symbol,
+        trade_id: t.id,
+        sequence: t.sequence,
+        buy_order_id: t.buy_order_id,
+        sell_order_id: t.sell_order_id,
+        aggressor_side: t.aggressor_side.as_str().to_string(),
+        lmm_quantity: t.lmm_quantity,
+    }
+}
+
+fn unavailable(_: BookStopped) -> Status {
+    Status::unavailable("Order book is not running")
+}
+
+fn to_proto_report(r: crate::models::ExecutionReport, format: PriceFormat) -> ExecutionReport {
+    ExecutionReport {
+        order_id: r.order_id,
+        user_id: r.user_id,
+        symbol: r.symbol,
+        side: r.side.as_str().to_string(),
+        exec_type: r.exec_type.as_str().to_string(),
+        price: r.price.map(|p| format.format(p)).unwrap_or_default(),
+        last_price: format.format(r.last_price),
+        last_quantity: r.last_quantity,
+        leaves_quantity: r.leaves_quantity,
+        cumulative_quantity: r.cumulative_quantity,
+        average_price: format.unscale(r.average_price),
+        reason: r.reason.unwrap_or_default(),
+        timestamp: r.timestamp.to_rfc3339(),
+    }
+}
+
+/// The public view of a trade, without the parties or their order ids.
+fn to_proto_print(t: crate::models::Trade, format: PriceFormat) -> TradePrint {
+    TradePrint {
+        trade_sequence: t.sequence,
+        price: format.format(t.price),
+        quantity: t.quantity,
+        aggressor_side: t.aggressor_side.as_str().to_string(),
+        timestamp: t.timestamp.to_rfc3339(),
+    }
+}
+
+fn to_proto_instrument(i: &Instrument) -> order::Instrument {
+    order::Instrument {
+        symbol: i.symbol.clone(),
+        price_scale: i.price_format.scale,
+        tick_size: i.price_format.format(i.price_format.tick_size),
+        lot_size: i.lot_size,
+        min_quantity: i.min_quantity,
+        max_quantity: i.max_quantity,
+        status: i.status.as_str().to_string(),
+        matching_mode: i.matching_mode.as_str().to_string(),
+        lead_market_makers: i.lead_market_makers.iter().flat_map(|lmm| lmm.user_ids.clone()).collect(),
+        lmm_percent: i.lead_market_makers.as_ref().map_or(0, |lmm| lmm.percent as u32),
+    }
+}
+
+fn to_proto_level(level: DepthLevel, format: PriceFormat) -> BookLevel {
+    BookLevel {
+        price: format.format(level.price),
+        quantity: level.quantity,
+        order_count: level.order_count as u32,
+    }
+}
+
+fn to_proto_book(symbol: &str, depth: Depth, format: PriceFormat) -> OrderBookResponse {
+    OrderBookResponse {
+        symbol: symbol.to_string(),
+        bids: depth.bids.into_iter().map(|l| to_proto_level(l, format)).collect(),
+        asks: depth.asks.into_iter().map(|l| to_proto_level(l, format)).collect(),
+    }
+}
+
+fn to_proto_quote(symbol: &str, quote: Quote, format: PriceFormat) -> order::Quote {
+    let price = |price: Option<u64>| price.map(|p| format.format(p)).unwrap_or_default();
+    order::Quote {
+        symbol: symbol.to_string(),
+        bid_price: price(quote.bid.as_ref().map(|l| l.price)),
+        bid_quantity: quote.bid.as_ref().map_or(0, |l| l.quantity),
+        ask_price: price(quote.ask.as_ref().map(|l| l.price)),
+        ask_quantity: quote.ask.as_ref().map_or(0, |l| l.quantity),
+        spread: price(quote.spread()),
+        last_trade_price: price(quote.last_trade_price),
+        last_trade_quantity: quote.last_trade_quantity.unwrap_or(0),
+    }
+}
+
+fn to_proto_market_data(symbol: &str, update: MarketDataUpdate, format: PriceFormat) -> MarketDataMessage {
+    let payload = match update.event {
+        MarketDataEvent::Level(LevelUpdate { side, action, level }) => Payload::Level(order::LevelUpdate {
+            side: side.as_str().to_string(),
+            action: action.as_str().to_string(),
+            level: Some(to_proto_level(level, format)),
+        }),
+        MarketDataEvent::Trade(trade) => Payload::Trade(to_proto_print(trade, format)),
+    };
+    MarketDataMessage { symbol: symbol.to_string(), sequence: update.sequence, payload: Some(payload) }
+}
+
+fn to_proto_order_event(event: OrderEvent, format: PriceFormat) -> order_feed_message::Payload {
+    use order_feed_message::Payload;
+    match event {
+        OrderEvent::Add { order_id, side, price, quantity } => Payload::Add(order::OrderAdd {
+            order_id,
+            side: side.as_str().to_string(),
+            price: format.format(price),
+            quantity,
+        }),
+        OrderEvent::Execute { order_id, quantity, trade_sequence } => {
+            Payload::Execute(order::OrderExecute { order_id, quantity, trade_sequence })
+        }
+        OrderEvent::Cancel { order_id, quantity } => Payload::Cancel(order::OrderCancel { order_id, quantity }),
+        OrderEvent::Replace { order_id, price, quantity } => {
+            Payload::Replace(order::OrderReplace { order_id, price: format.format(price), quantity })
+        }
+    }
+}
+
+/// Streams `first` and then every feed update to a subscriber. A subscriber
+/// that falls too far behind gets `DATA_LOSS` and must resubscribe.
+fn stream_feed<E, M>(
+    first: M,
+    mut updates: broadcast::Receiver<Sequenced<E>>,
+    convert: impl Fn(Sequenced<E>) -> M + Send + 'static,
+) -> ReceiverStream<Result<M, Status>>
+where
+    E: Clone + Send + 'static,
+    M: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(MARKET_DATA_STREAM_BUFFER);
+    tokio::spawn(async move {
+        if tx.send(Ok(first)).await.is_err() {
+            return;
+        }
+        loop {
+            let message = match updates.recv().await {
+                Ok(update) => Ok(convert(update)),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    Err(Status::data_loss("subscriber fell behind; resubscribe for a new snapshot"))
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let lagged = message.is_err();
+            if tx.send(message).await.is_err() || lagged {
+                return;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Keeps only the reports about the caller's own order; counterparties get
+/// theirs through their Redis report stream.
+fn reports_for<T>(outcome: &mut BookOutcome<T>, order_id: &str, format: PriceFormat) -> Vec<ExecutionReport> {
+    std::mem::take(&mut outcome.reports)
+        .into_iter()
+        .filter(|r| r.order_id == order_id)
+        .map(|r| to_proto_report(r, format))
+        .collect()
+}
+
+/// The user a session request acts for.
+fn action_user(action: &Option<Action>) -> Option<&str> {
+    match action {
+        Some(Action::Submit(req)) => Some(&req.user_id),
+        Some(Action::Cancel(req)) => Some(&req.user_id),
+        Some(Action::Amend(req)) => Some(&req.user_id),
+        None => None,
+    }
+}
+
+/// The execution reports a session reply already carries.
+fn reply_reports(reply: &Reply) -> &[ExecutionReport] {
+    match reply {
+        Reply::Submitted(response) => &response.reports,
+        Reply::Cancelled(response) => &response.reports,
+        Reply::Amended(response) => &response.reports,
+        Reply::Rejected(_) | Reply::Report(_) => &[],
+    }
+}
+
+/// The sending half of an order session, numbering its messages.
+struct SessionStream {
+    sequence: u64,
+    tx: mpsc::Sender<Result<SessionMessage, Status>>,
+}
+
+impl SessionStream {
+    /// Returns false once the client has gone away.
+    async fn send(&mut self, request_id: String, reply: Reply) -> bool {
+        self.sequence += 1;
+        let message = SessionMessage { sequence: self.sequence, request_id, reply: Some(reply) };
+        self.tx.send(Ok(message)).await.is_ok()
+    }
+
+    async fn fell_behind(self) {
+        let _ = self.tx.send(Err(Status::data_loss("session fell behind on execution reports"))).await;
+    }
+}
 
 pub struct OrderService {
-    pub book: Arc<Mutex<OrderBook>>,
-    pub redis: RedisWriter,
+    pub books: BookManager,
+}
+
+impl OrderService {
+    /// Handles one order session request the same way as the matching
+    /// unary RPC, turning an error status into a reject.
+    async fn session_reply(&self, action: Option<Action>) -> Reply {
+        let reply = match action {
+            Some(Action::Submit(req)) => self.submit_order(Request::new(req)).await.map(|r| Reply::Submitted(r.into_inner())),
+            Some(Action::Cancel(req)) => self.cancel_order(Request::new(req)).await.map(|r| Reply::Cancelled(r.into_inner())),
+            Some(Action::Amend(req)) => self.amend_order(Request::new(req)).await.map(|r| Reply::Amended(r.into_inner())),
+            None => Err(Status::invalid_argument("Session request has no action")),
+        };
+        reply.unwrap_or_else(|status| Reply::Rejected(SessionReject {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        }))
+    }
+
+    /// `report` as a session message if it is about `user`'s order and not
+    /// already among the `carried` reports of a reply.
+    fn session_report(&self, user: Option<&str>, report: crate::models::ExecutionReport, carried: &[ExecutionReport]) -> Option<Reply> {
+        if user != Some(report.user_id.as_str()) {
+            return None;
+        }
+        let format = self.books.book(&report.symbol)?.price_format();
+        let report = to_proto_report(report, format);
+        (!carried.contains(&report)).then_some(Reply::Report(report))
+    }
+
+    /// Runs an order session over `requests`; see `order_session`.
+    fn session<S>(&self, mut requests: S) -> ReceiverStream<Result<SessionMessage, Status>>
+    where
+        S: Stream<Item = Result<SessionRequest, Status>> + Unpin + Send + 'static,
+    {
+        let service = OrderService { books: self.books.clone() };
+        // Subscribed before the first request, so none of its reports are missed
+        let mut reports = self.books.subscribe_reports();
+        let (tx, rx) = mpsc::channel(SESSION_BUFFER);
+
+        tokio::spawn(async move {
+            let mut stream = SessionStream { sequence: 0, tx };
+            let mut user: Option<String> = None;
+            loop {
+                tokio::select! {
+                    req = requests.next() => {
+                        // Ends when the client closes its side or the connection drops
+                        let Some(Ok(req)) = req else { return };
+                        if user.is_none() {
+                            user = action_user(&req.action).map(str::to_string);
+                        }
+                        let reply = match action_user(&req.action) {
+                            Some(other) if Some(other) != user.as_deref() => Reply::Rejected(SessionReject {
+                                code: tonic::Code::PermissionDenied as i32,
+                                message: "Session belongs to another user".to_string(),
+                            }),
+                            _ => service.session_reply(req.action).await,
+                        };
+
+                        // Books publish a command's reports before replying, so
+                        // everything the request caused is already queued
+                        loop {
+                            let report = match reports.try_recv() {
+                                Ok(report) => report,
+                                Err(broadcast::error::TryRecvError::Lagged(_)) => return stream.fell_behind().await,
+                                Err(_) => break,
+                            };
+                            if let Some(message) = service.session_report(user.as_deref(), report, reply_reports(&reply)) {
+                                if !stream.send(String::new(), message).await {
+                                    return;
+                                }
+                            }
+                        }
+                        if !stream.send(req.request_id, reply).await {
+                            return;
+                        }
+                    }
+                    report = reports.recv() => {
+                        let report = match report {
+                            Ok(report) => report,
+                            Err(broadcast::error::RecvError::Lagged(_)) => return stream.fell_behind().await,
+                            Err(broadcast::error::RecvError::Closed) => return,
+                        };
+                        if let Some(message) = service.session_report(user.as_deref(), report, &[]) {
+                            if !stream.send(String::new(), message).await {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
 }
 
 #[tonic::async_trait]
 impl OrderMatching for OrderService {
     async fn submit_order(&self, request: Request<OrderRequest>) -> Result<Response<SubmitResponse>, Status> {
         let req = request.into_inner();
+        let symbol = normalize_symbol(&req.symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::invalid_argument("Unknown symbol"))?;
+        let format = book.price_format();
 
         // Convert gRPC request to internal Order
         let side = match req.side.to_lowercase().as_str() {
@@ -33,48 +365,255 @@ impl OrderMatching for OrderService {
         let order_type = match req.order_type.to_lowercase().as_str() {
             "limit" => OrderType::Limit,
             "market" => OrderType::Market,
+            "stop" => OrderType::Stop,
+            "stop_limit" => OrderType::StopLimit,
             _ => return Err(Status::invalid_argument("Invalid order_type")),
         };
 
-        let order = Order::new(
+        let time_in_force = match req.time_in_force.to_lowercase().as_str() {
+            "" | "gtc" => TimeInForce::Gtc,
+            "ioc" => TimeInForce::Ioc,
+            "fok" => TimeInForce::Fok,
+            "day" => TimeInForce::Day,
+            "gtd" => match chrono::DateTime::parse_from_rfc3339(&req.expire_time) {
+                Ok(expiry) => TimeInForce::Gtd(expiry.with_timezone(&chrono::Utc)),
+                Err(_) => return Err(Status::invalid_argument("GTD orders need an RFC 3339 expire_time")),
+            },
+            _ => return Err(Status::invalid_argument("Invalid time_in_force")),
+        };
+
+        let has_price = matches!(order_type, OrderType::Limit | OrderType::StopLimit);
+        let price = if has_price { parse_price(format, &req.price, "price").map_err(Status::invalid_argument)? } else { None };
+        let trigger_price = parse_price(format, &req.trigger_price, "trigger_price").map_err(Status::invalid_argument)?;
+        let mut order = Order::new(
             req.user_id,
-            req.symbol,
+            symbol,
             side,
             order_type.clone(), // clone it before it's moved
-            if order_type == OrderType::Limit { Some(req.price) } else { None },
+            price,
             req.quantity,
-        );
+        ).with_time_in_force(time_in_force);
+        if let (true, Some(trigger_price)) = (order.is_stop(), trigger_price) {
+            order = order.with_trigger_price(trigger_price);
+        }
+        match req.post_only.to_lowercase().as_str() {
+            "" => {}
+            "reject" => order = order.with_post_only(PostOnly::Reject),
+            "slide" => order = order.with_post_only(PostOnly::Slide),
+            _ => return Err(Status::invalid_argument("Invalid post_only")),
+        }
+        let stp = match req.self_trade_prevention.to_lowercase().as_str() {
+            "" => None,
+            "cancel_newest" => Some(SelfTradePrevention::CancelNewest),
+            "cancel_oldest" => Some(SelfTradePrevention::CancelOldest),
+            "cancel_both" => Some(SelfTradePrevention::CancelBoth),
+            "decrement_and_cancel" => Some(SelfTradePrevention::DecrementAndCancel),
+            _ => return Err(Status::invalid_argument("Invalid self_trade_prevention")),
+        };
+        if let Some(policy) = stp {
+            order = order.with_self_trade_prevention(policy);
+        }
+        if !req.stp_group.is_empty() {
+            order = order.with_stp_group(req.stp_group.clone());
+        }
+        if req.display_quantity > 0 {
+            order = order.with_display_quantity(req.display_quantity);
+        }
 
-        // Submit to matching engine
-        let mut book = self.book.lock().await;
-        let trades = book.submit_order(order.clone());
+        // Validate against the instrument's reference data
+        let instrument = book.instrument();
+        instrument.check_open().map_err(Status::failed_precondition)?;
+        instrument.check_order(&order).map_err(Status::invalid_argument)?;
 
-        // Publish to Redis
-        for trade in &trades {
-            self.redis.publish_trade(trade).await;
-            self.redis.update_user_pnl(trade).await;
-            self.redis.publish_trade(trade).await;
-        }
+        // Submit to the symbol's matching engine
+        let order_id = order.id.clone();
+        let mut outcome = book.submit_order(order).await.map_err(unavailable)?;
+        let reports = reports_for(&mut outcome, &order_id, format);
 
         // Return gRPC response
         let response = SubmitResponse {
-            trades: trades.into_iter().map(|t| Trade {
-                price: t.price,
-                quantity: t.quantity,
-                buyer: t.buyer,
-                seller: t.seller,
-                timestamp: t.timestamp.to_rfc3339(),
-            }).collect()
+            order_id,
+            trades: outcome.result.into_iter().map(|t| to_proto_trade(t, format)).collect(),
+            reports,
         };
 
         Ok(Response::new(response))
     }
+
+    async fn cancel_order(&self, request: Request<CancelRequest>) -> Result<Response<CancelResponse>, Status> {
+        let req = request.into_inner();
+
+        let book = self.books.book(&normalize_symbol(&req.symbol)).ok_or_else(|| Status::invalid_argument("Unknown symbol"))?;
+        let mut outcome = book.cancel_order(req.order_id.clone(), req.user_id).await.map_err(unavailable)?;
+        let reports = reports_for(&mut outcome, &req.order_id, book.price_format());
+        let cancelled = match outcome.result {
+            Ok(order) => order,
+            Err(CancelError::NotFound) => return Err(Status::not_found("Order not found")),
+            Err(CancelError::NotOwner) => return Err(Status::permission_denied("Order belongs to another user")),
+        };
+
+        Ok(Response::new(CancelResponse {
+            order_id: cancelled.id,
+            remaining_quantity: cancelled.quantity,
+            reports,
+        }))
+    }
+
+    async fn amend_order(&self, request: Request<AmendRequest>) -> Result<Response<AmendResponse>, Status> {
+        let req = request.into_inner();
+
+        let book = self.books.book(&normalize_symbol(&req.symbol)).ok_or_else(|| Status::invalid_argument("Unknown symbol"))?;
+        let format = book.price_format();
+        let new_price = parse_price(format, &req.price, "price").map_err(Status::invalid_argument)?;
+        let new_quantity = if req.quantity > 0 { Some(req.quantity) } else { None };
+
+        let instrument = book.instrument();
+        instrument.check_open().map_err(Status::failed_precondition)?;
+        if let Some(price) = new_price {
+            instrument.check_price(price).map_err(Status::invalid_argument)?;
+        }
+        if let Some(quantity) = new_quantity {
+            instrument.check_quantity(quantity).map_err(Status::invalid_argument)?;
+        }
+
+        let mut outcome = book.amend_order(req.order_id.clone(), req.user_id, new_price, new_quantity).await.map_err(unavailable)?;
+        let reports = reports_for(&mut outcome, &req.order_id, format);
+        let trades = match outcome.result {
+            Ok(trades) => trades,
+            Err(AmendError::NotFound) => return Err(Status::not_found("Order not found")),
+            Err(AmendError::InvalidQuantity) => return Err(Status::invalid_argument("Invalid quantity")),
+            Err(AmendError::InvalidPrice) => return Err(Status::invalid_argument("Invalid price: stop orders cannot be repriced and prices must be on tick")),
+            Err(AmendError::WouldTakeLiquidity) => return Err(Status::failed_precondition("post-only order would take liquidity")),
+            Err(AmendError::NotOwner) => return Err(Status::permission_denied("Order belongs to another user")),
+            Err(AmendError::NotOpen) => return Err(Status::failed_precondition(format!("{} is not open for trading", instrument.symbol))),
+        };
+
+        Ok(Response::new(AmendResponse {
+            order_id: req.order_id,
+            trades: trades.into_iter().map(|t| to_proto_trade(t, format)).collect(),
+            reports,
+        }))
+    }
+
+    type OrderSessionStream = ReceiverStream<Result<SessionMessage, Status>>;
+
+    /// Order entry over one long-lived stream. Requests are handled one at a
+    /// time in the order they arrive, so a client can pipeline them without
+    /// waiting for replies. A rejected request does not end the session.
+    ///
+    /// The session belongs to the user of its first request and refuses
+    /// requests for anyone else. Reports about the user's orders that no
+    /// reply carries, such as fills on resting orders taken by other
+    /// clients, come down the same stream as they happen.
+    async fn order_session(&self, request: Request<tonic::Streaming<SessionRequest>>) -> Result<Response<Self::OrderSessionStream>, Status> {
+        Ok(Response::new(self.session(request.into_inner())))
+    }
+
+    async fn list_instruments(&self, _request: Request<ListInstrumentsRequest>) -> Result<Response<ListInstrumentsResponse>, Status> {
+        let instruments = self.books.instruments().iter().map(to_proto_instrument).collect();
+        Ok(Response::new(ListInstrumentsResponse { instruments }))
+    }
+
+    async fn get_instrument(&self, request: Request<GetInstrumentRequest>) -> Result<Response<order::Instrument>, Status> {
+        let symbol = normalize_symbol(&request.into_inner().symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        Ok(Response::new(to_proto_instrument(&book.instrument())))
+    }
+
+    async fn get_order_book(&self, request: Request<OrderBookRequest>) -> Result<Response<OrderBookResponse>, Status> {
+        let req = request.into_inner();
+        let symbol = normalize_symbol(&req.symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let levels = if req.depth == 0 { DEFAULT_DEPTH } else { req.depth as usize };
+
+        let depth = book.depth(levels).await.map_err(unavailable)?;
+        Ok(Response::new(to_proto_book(&symbol, depth, book.price_format())))
+    }
+
+    async fn get_quote(&self, request: Request<GetQuoteRequest>) -> Result<Response<order::Quote>, Status> {
+        let symbol = normalize_symbol(&request.into_inner().symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let quote = book.quote().await.map_err(unavailable)?;
+        Ok(Response::new(to_proto_quote(&symbol, quote, book.price_format())))
+    }
+
+    type SubscribeMarketDataStream = ReceiverStream<Result<MarketDataMessage, Status>>;
+
+    async fn subscribe_market_data(&self, request: Request<MarketDataRequest>) -> Result<Response<Self::SubscribeMarketDataStream>, Status> {
+        let symbol = normalize_symbol(&request.into_inner().symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let format = book.price_format();
+        let subscription = book.subscribe_market_data().await.map_err(unavailable)?;
+
+        let snapshot = MarketDataMessage {
+            symbol: symbol.clone(),
+            sequence: subscription.sequence,
+            payload: Some(Payload::Snapshot(to_proto_book(&symbol, subscription.snapshot, format))),
+        };
+        let stream = stream_feed(snapshot, subscription.updates, move |update| to_proto_market_data(&symbol, update, format));
+        Ok(Response::new(stream))
+    }
+
+    type SubscribeOrderFeedStream = ReceiverStream<Result<OrderFeedMessage, Status>>;
+
+    async fn subscribe_order_feed(&self, request: Request<MarketDataRequest>) -> Result<Response<Self::SubscribeOrderFeedStream>, Status> {
+        let symbol = normalize_symbol(&request.into_inner().symbol);
+        let book = self.books.book(&symbol).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let format = book.price_format();
+        let subscription = book.subscribe_order_feed().await.map_err(unavailable)?;
+
+        let orders = subscription.snapshot.into_iter()
+            .filter_map(|event| match to_proto_order_event(event, format) {
+                order_feed_message::Payload::Add(add) => Some(add),
+                _ => None,
+            })
+            .collect();
+        let snapshot = OrderFeedMessage {
+            symbol: symbol.clone(),
+            sequence: subscription.sequence,
+            payload: Some(order_feed_message::Payload::Snapshot(OrderSnapshot { orders })),
+        };
+        let stream = stream_feed(snapshot, subscription.updates, move |update| OrderFeedMessage {
+            symbol: symbol.clone(),
+            sequence: update.sequence,
+            payload: Some(to_proto_order_event(update.event, format)),
+        });
+        Ok(Response::new(stream))
+    }
+
+    async fn set_trading_status(&self, request: Request<SetTradingStatusRequest>) -> Result<Response<order::Instrument>, Status> {
+        let req = request.into_inner();
+        let status = match req.status.to_lowercase().as_str() {
+            "open" => TradingStatus::Open,
+            "halted" => TradingStatus::Halted,
+            "closed" => TradingStatus::Closed,
+            _ => return Err(Status::invalid_argument("Invalid status")),
+        };
+
+        let book = self.books.book(&normalize_symbol(&req.symbol)).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let instrument = book.set_trading_status(status).await.map_err(unavailable)?;
+        Ok(Response::new(to_proto_instrument(&instrument)))
+    }
+
+    async fn set_matching_mode(&self, request: Request<SetMatchingModeRequest>) -> Result<Response<order::Instrument>, Status> {
+        let req = request.into_inner();
+        let mode = match req.matching_mode.to_lowercase().as_str() {
+            "fifo" => MatchingMode::Fifo,
+            "pro_rata" => MatchingMode::ProRata,
+            "size_priority" => MatchingMode::SizePriority,
+            _ => return Err(Status::invalid_argument("Invalid matching_mode")),
+        };
+
+        let book = self.books.book(&normalize_symbol(&req.symbol)).ok_or_else(|| Status::not_found("Unknown symbol"))?;
+        let instrument = book.set_matching_mode(mode).await.map_err(unavailable)?.map_err(Status::failed_precondition)?;
+        Ok(Response::new(to_proto_instrument(&instrument)))
+    }
 }
 
 // Expose gRPC server runner
-pub async fn serve(book: Arc<Mutex<OrderBook>>, redis: RedisWriter) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn serve(books: BookManager) -> Result<(), Box<dyn std::error::Error>> {
     let addr = "[::1]:50051".parse()?;
-    let service = OrderService { book, redis };
+    let service = OrderService { books };
 
     println!("gRPC server running on {}", addr);
 
@@ -85,3 +624,125 @@ pub async fn serve(book: Arc<Mutex<OrderBook>>, redis: RedisWriter) -> Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::InstrumentRegistry;
+
+    fn service() -> OrderService {
+        let (events, _) = crate::redis_writer::event_queue(1);
+        let registry = InstrumentRegistry::from_json(r#"[{"symbol": "AAPL", "price_scale": 2, "tick_size": "0.01"}]"#).unwrap();
+        OrderService { books: BookManager::new(&registry, events) }
+    }
+
+    #[tokio::test]
+    async fn test_stp_group_spans_users_without_a_resting_policy() {
+        let service = service();
+        let order = |user: &str, side: &str, policy: &str| OrderRequest {
+            user_id: user.to_string(),
+            symbol: "aapl".to_string(),
+            side: side.to_string(),
+            order_type: "limit".to_string(),
+            quantity: 5,
+            price: "150.00".to_string(),
+            self_trade_prevention: policy.to_string(),
+            stp_group: "firm1".to_string(),
+            ..Default::default()
+        };
+        service.submit_order(Request::new(order("trader_a", "sell", ""))).await.unwrap();
+        let taker = service.submit_order(Request::new(order("trader_b", "buy", "cancel_newest"))).await.unwrap().into_inner();
+        assert!(taker.trades.is_empty());
+        assert_eq!(taker.reports.last().unwrap().exec_type, "cancelled");
+    }
+
+    fn limit(user: &str, side: &str, price: &str, quantity: u64) -> Action {
+        Action::Submit(OrderRequest {
+            user_id: user.to_string(),
+            symbol: "aapl".to_string(),
+            side: side.to_string(),
+            order_type: "limit".to_string(),
+            quantity,
+            price: price.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_session_replies_match_the_unary_rpcs() {
+        let service = service();
+        let Reply::Submitted(resting) = service.session_reply(Some(limit("s1", "sell", "150.00", 10))).await else {
+            panic!("sell was not accepted");
+        };
+        let Reply::Submitted(taker) = service.session_reply(Some(limit("b1", "buy", "150.00", 4))).await else {
+            panic!("buy was not accepted");
+        };
+        assert_eq!(taker.trades.len(), 1);
+        assert_eq!(taker.trades[0].price, "150.00");
+        assert_eq!(taker.trades[0].symbol, "AAPL");
+        assert!(taker.reports.iter().all(|r| r.order_id == taker.order_id));
+
+        let stranger = CancelRequest { order_id: resting.order_id.clone(), symbol: " aapl".to_string(), user_id: "b1".to_string() };
+        let Reply::Rejected(reject) = service.session_reply(Some(Action::Cancel(stranger))).await else {
+            panic!("cancelled another user's order");
+        };
+        assert_eq!(reject.code, tonic::Code::PermissionDenied as i32);
+
+        let cancel = Action::Cancel(CancelRequest { order_id: resting.order_id, symbol: "AAPL".to_string(), user_id: "s1".to_string() });
+        let Reply::Cancelled(cancelled) = service.session_reply(Some(cancel)).await else {
+            panic!("cancel was not accepted");
+        };
+        assert_eq!(cancelled.remaining_quantity, 6);
+    }
+
+    #[tokio::test]
+    async fn test_session_reports_fills_caused_by_other_clients() {
+        let service = service();
+        let (requests, rx) = mpsc::channel(8);
+        let mut session = service.session(ReceiverStream::new(rx));
+        let request = |request_id: &str, action| SessionRequest { request_id: request_id.to_string(), action: Some(action) };
+
+        requests.send(Ok(request("r1", limit("s1", "sell", "150.00", 10)))).await.unwrap();
+        let message = session.next().await.unwrap().unwrap();
+        assert_eq!((message.sequence, message.request_id.as_str()), (1, "r1"));
+        let Some(Reply::Submitted(resting)) = message.reply else {
+            panic!("sell was not accepted");
+        };
+
+        // Another client takes part of the resting order
+        let Reply::Submitted(_) = service.session_reply(Some(limit("b1", "buy", "150.00", 4))).await else {
+            panic!("buy was not accepted");
+        };
+        let message = session.next().await.unwrap().unwrap();
+        assert_eq!((message.sequence, message.request_id.as_str()), (2, ""));
+        let Some(Reply::Report(report)) = message.reply else {
+            panic!("passive fill was not reported");
+        };
+        assert_eq!(report.order_id, resting.order_id);
+        assert_eq!((report.exec_type.as_str(), report.last_quantity, report.leaves_quantity), ("partial_fill", 4, 6));
+
+        requests.send(Ok(request("r2", limit("b1", "buy", "150.00", 1)))).await.unwrap();
+        let message = session.next().await.unwrap().unwrap();
+        assert_eq!((message.sequence, message.request_id.as_str()), (3, "r2"));
+        let Some(Reply::Rejected(reject)) = message.reply else {
+            panic!("session accepted another user's order");
+        };
+        assert_eq!(reject.code, tonic::Code::PermissionDenied as i32);
+    }
+
+    #[tokio::test]
+    async fn test_session_rejects_carry_the_status_code() {
+        let service = service();
+        let Reply::Rejected(reject) = service.session_reply(Some(limit("b1", "buy", "150.001", 4))).await else {
+            panic!("off-scale price was accepted");
+        };
+        assert_eq!(reject.code, tonic::Code::InvalidArgument as i32);
+
+        let cancel = Action::Cancel(CancelRequest { order_id: "missing".to_string(), symbol: "AAPL".to_string(), user_id: "b1".to_string() });
+        let Reply::Rejected(reject) = service.session_reply(Some(cancel)).await else {
+            panic!("unknown order was cancelled");
+        };
+        assert_eq!(reject.code, tonic::Code::NotFound as i32);
+        assert!(matches!(service.session_reply(None).await, Reply::Rejected(_)));
+    }
+}
@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+
+use crate::allocation::{AllocationStrategy, LmmAllocation, LmmPolicy, ProRataAllocation, ProRataPolicy};
+use crate::engine::MatchingMode;
+use crate::models::Order;
+use crate::price::PriceFormat;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    Open,
+    /// Resting orders can be cancelled, but nothing new is accepted.
+    Halted,
+    Closed,
+}
+
+impl TradingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TradingStatus::Open => "open",
+            TradingStatus::Halted => "halted",
+            TradingStatus::Closed => "closed",
+        }
+    }
+}
+
+/// Symbols are case-insensitive and stored upper case, e.g. `"aapl "` is
+/// `"AAPL"`.
+pub fn normalize_symbol(symbol: &str) -> String {
+    symbol.trim().to_uppercase()
+}
+
+/// Reference data for one tradable symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub symbol: String,
+    pub price_format: PriceFormat,
+    /// Order quantities must be a multiple of this.
+    pub lot_size: u64,
+    pub min_quantity: u64,
+    pub max_quantity: u64,
+    pub status: TradingStatus,
+    pub matching_mode: MatchingMode,
+    /// Used whenever the book matches in pro-rata mode.
+    pub pro_rata: ProRataPolicy,
+    /// Allocated their share before the matching mode sees the residual.
+    pub lead_market_makers: Option<LmmPolicy>,
+}
+
+impl Instrument {
+    /// New orders and amends are only accepted while the instrument is open.
+    pub fn check_open(&self) -> Result<(), String> {
+        if self.status != TradingStatus::Open {
+            return Err(format!("{} is {}", self.symbol, self.status.as_str()));
+        }
+        Ok(())
+    }
+
+    /// Checks an incoming order against the instrument's quantity and price
+    /// rules. Trading status is checked separately, since callers report it
+    /// differently.
+    pub fn check_order(&self, order: &Order) -> Result<(), &'static str> {
+        self.check_quantity(order.quantity)?;
+        if order.display_quantity.is_some_and(|display| !display.is_multiple_of(self.lot_size)) {
+            return Err("display quantity is not a multiple of the lot size");
+        }
+        for price in [order.price, order.trigger_price].into_iter().flatten() {
+            self.check_price(price)?;
+        }
+        Ok(())
+    }
+
+    pub fn check_quantity(&self, quantity: u64) -> Result<(), &'static str> {
+        if quantity < self.min_quantity {
+            return Err("quantity is below the instrument minimum");
+        }
+        if quantity > self.max_quantity {
+            return Err("quantity is above the instrument maximum");
+        }
+        if !quantity.is_multiple_of(self.lot_size) {
+            return Err("quantity is not a multiple of the lot size");
+        }
+        Ok(())
+    }
+
+    pub fn check_price(&self, price: u64) -> Result<(), &'static str> {
+        if !price.is_multiple_of(self.price_format.tick_size) {
+            return Err("price is not a multiple of the tick size");
+        }
+        Ok(())
+    }
+
+    /// Builds the allocation strategy for the instrument's matching mode,
+    /// behind its lead market makers if it has any.
+    pub fn allocation_strategy(&self) -> Box<dyn AllocationStrategy> {
+        let strategy = match self.matching_mode {
+            MatchingMode::ProRata => Box::new(ProRataAllocation { policy: self.pro_rata, lot_size: self.lot_size }),
+            mode => mode.strategy(),
+        };
+        match &self.lead_market_makers {
+            Some(lmm) => Box::new(LmmAllocation {
+                makers: lmm.user_ids.iter().cloned().collect(),
+                percent: lmm.percent,
+                residual: strategy,
+            }),
+            None => strategy,
+        }
+    }
+}
+
+/// One entry of the instruments config file. The tick size is written as a
+/// decimal price, e.g. `"0.05"`.
+#[derive(Deserialize)]
+struct InstrumentConfig {
+    symbol: String,
+    price_scale: u32,
+    tick_size: String,
+    #[serde(default = "one")]
+    lot_size: u64,
+    #[serde(default = "one")]
+    min_quantity: u64,
+    #[serde(default = "unlimited")]
+    max_quantity: u64,
+    #[serde(default = "open")]
+    status: TradingStatus,
+    #[serde(default = "fifo")]
+    matching_mode: MatchingMode,
+    #[serde(default)]
+    pro_rata: ProRataPolicy,
+    #[serde(default)]
+    lead_market_makers: Option<LmmPolicy>,
+}
+
+fn one() -> u64 {
+    1
+}
+
+fn unlimited() -> u64 {
+    u64::MAX
+}
+
+fn open() -> TradingStatus {
+    TradingStatus::Open
+}
+
+fn fifo() -> MatchingMode {
+    MatchingMode::Fifo
+}
+
+impl InstrumentConfig {
+    fn into_instrument(self) -> Result<Instrument, String> {
+        let invalid = |reason: &str| format!("{}: {}", self.symbol, reason);
+
+        if self.price_scale > 18 {
+            return Err(invalid("price scale must be at most 18"));
+        }
+        let unscaled = PriceFormat::new(self.price_scale, 1);
+        let tick_size = unscaled.parse(&self.tick_size).map_err(&invalid)?;
+        if tick_size == 0 {
+            return Err(invalid("tick size must be positive"));
+        }
+        if self.lot_size == 0 {
+            return Err(invalid("lot size must be positive"));
+        }
+        if self.min_quantity == 0 || self.min_quantity > self.max_quantity {
+            return Err(invalid("quantity limits must satisfy 0 < min_quantity <= max_quantity"));
+        }
+        if self.pro_rata.fifo_percent > 100 {
+            return Err(invalid("pro_rata.fifo_percent must be at most 100"));
+        }
+        if let Some(lmm) = &self.lead_market_makers {
+            if lmm.percent > 100 {
+                return Err(invalid("lead_market_makers.percent must be at most 100"));
+            }
+            if lmm.user_ids.is_empty() {
+                return Err(invalid("lead_market_makers.user_ids must not be empty"));
+            }
+        }
+
+        Ok(Instrument {
+            symbol: normalize_symbol(&self.symbol),
+            price_format: PriceFormat::new(self.price_scale, tick_size),
+            lot_size: self.lot_size,
+            min_quantity: self.min_quantity,
+            max_quantity: self.max_quantity,
+            status: self.status,
+            matching_mode: self.matching_mode,
+            pro_rata: self.pro_rata,
+            lead_market_makers: self.lead_market_makers,
+        })
+    }
+}
+
+/// Every instrument the engine trades, loaded once at startup.
+#[derive(Debug, Clone)]
+pub struct InstrumentRegistry {
+    instruments: Vec<Instrument>,
+}
+
+impl InstrumentRegistry {
+    /// Reads a JSON array of instruments from `path`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Self::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let configs: Vec<InstrumentConfig> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let mut instruments = configs.into_iter()
+            .map(InstrumentConfig::into_instrument)
+            .collect::<Result<Vec<_>, _>>()?;
+        instruments.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        if let Some(pair) = instruments.windows(2).find(|pair| pair[0].symbol == pair[1].symbol) {
+            return Err(format!("{}: listed more than once", pair[0].symbol));
+        }
+        Ok(Self { instruments })
+    }
+
+    /// Instruments in symbol order.
+    pub fn iter(&self) -> impl Iterator<Item = &Instrument> {
+        self.instruments.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocation::LeftoverRule;
+    use crate::models::{OrderType, Side};
+
+    const CONFIG: &str = r#"[
+        {"symbol": "msft", "price_scale": 2, "tick_size": "0.05", "lot_size": 10,
+         "min_quantity": 10, "max_quantity": 1000, "status": "halted", "matching_mode": "pro_rata",
+         "pro_rata": {"top_order": true, "fifo_percent": 40, "min_allocation": 2, "leftover": "time_priority"},
+         "lead_market_makers": {"user_ids": ["mm1", "mm2"], "percent": 30}},
+        {"symbol": "AAPL", "price_scale": 4, "tick_size": "0.01"}
+    ]"#;
+
+    fn limit(qty: u64, price: u64) -> Order {
+        Order::new("u1".to_string(), "MSFT".to_string(), Side::Buy, OrderType::Limit, Some(price), qty)
+    }
+
+    #[test]
+    fn test_load_config_with_defaults() {
+        let registry = InstrumentRegistry::from_json(CONFIG).unwrap();
+        let instruments: Vec<&Instrument> = registry.iter().collect();
+        assert_eq!(instruments.len(), 2);
+
+        let aapl = instruments[0];
+        assert_eq!(aapl.symbol, "AAPL");
+        assert_eq!(aapl.price_format, PriceFormat::new(4, 100));
+        assert_eq!((aapl.lot_size, aapl.min_quantity, aapl.max_quantity), (1, 1, u64::MAX));
+        assert_eq!(aapl.status, TradingStatus::Open);
+        assert_eq!(aapl.matching_mode, MatchingMode::Fifo);
+
+        let msft = instruments[1];
+        assert_eq!(msft.symbol, "MSFT");
+        assert_eq!(msft.price_format, PriceFormat::new(2, 5));
+        assert_eq!(msft.status, TradingStatus::Halted);
+        assert_eq!(msft.matching_mode, MatchingMode::ProRata);
+        assert!(msft.pro_rata.top_order);
+        assert_eq!(msft.pro_rata.top_order_max, None);
+        assert_eq!(msft.pro_rata.fifo_percent, 40);
+        assert_eq!(msft.pro_rata.min_allocation, 2);
+        assert_eq!(msft.pro_rata.leftover, LeftoverRule::TimePriority);
+        assert_eq!(aapl.pro_rata, ProRataPolicy::default());
+        assert_eq!(msft.lead_market_makers, Some(LmmPolicy { user_ids: vec!["mm1".to_string(), "mm2".to_string()], percent: 30 }));
+        assert_eq!(aapl.lead_market_makers, None);
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        assert!(InstrumentRegistry::from_json("not json").is_err());
+        assert!(InstrumentRegistry::from_json(r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.001"}]"#).is_err());
+        assert!(InstrumentRegistry::from_json(r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0"}]"#).is_err());
+        assert!(InstrumentRegistry::from_json(r#"[{"symbol": "A", "price_scale": 19, "tick_size": "1"}]"#).is_err());
+        assert!(InstrumentRegistry::from_json(r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.01", "lot_size": 0}]"#).is_err());
+        assert!(InstrumentRegistry::from_json(
+            r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.01", "min_quantity": 5, "max_quantity": 4}]"#
+        ).is_err());
+        assert!(InstrumentRegistry::from_json(
+            r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.01", "lead_market_makers": {"user_ids": ["mm"], "percent": 101}}]"#
+        ).is_err());
+        assert!(InstrumentRegistry::from_json(
+            r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.01", "lead_market_makers": {"user_ids": [], "percent": 10}}]"#
+        ).is_err());
+        assert!(InstrumentRegistry::from_json(
+            r#"[{"symbol": "A", "price_scale": 2, "tick_size": "0.01"}, {"symbol": "a", "price_scale": 2, "tick_size": "0.01"}]"#
+        ).is_err());
+    }
+
+    #[test]
+    fn test_check_order() {
+        let registry = InstrumentRegistry::from_json(CONFIG).unwrap();
+        let msft = registry.iter().find(|i| i.symbol == "MSFT").unwrap();
+
+        assert_eq!(msft.check_order(&limit(20, 10005)), Ok(()));
+        assert!(msft.check_order(&limit(5, 10005)).is_err());
+        assert!(msft.check_order(&limit(25, 10005)).is_err());
+        assert!(msft.check_order(&limit(2000, 10005)).is_err());
+        assert!(msft.check_order(&limit(20, 10003)).is_err());
+        assert!(msft.check_order(&limit(20, 10005).with_display_quantity(5)).is_err());
+    }
+}
@@ -0,0 +1,11 @@
+pub mod allocation;
+pub mod book_manager;
+pub mod engine;
+pub mod feed_handler;
+pub mod grpc_server;
+pub mod instrument;
+pub mod market_data;
+pub mod price;
+pub mod price_level;
+pub mod redis_writer;
+pub mod models;
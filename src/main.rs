@@ -1,31 +1,25 @@
 use std::env;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
-mod engine;
-mod grpc_server;
-mod redis_writer;
-mod models;
-
-use crate::engine::{OrderBook, MatchingMode};
-use crate::grpc_server::serve;
-use crate::redis_writer::RedisWriter;
+use real_time_engine::book_manager::BookManager;
+use real_time_engine::grpc_server::serve;
+use real_time_engine::instrument::InstrumentRegistry;
+use real_time_engine::redis_writer::RedisWriter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse matching mode from command line
-    let mode_str = env::args().nth(1).unwrap_or_else(|| "fifo".to_string());
-    let mode = match mode_str.to_lowercase().as_str() {
-        "pro" | "prorata" => MatchingMode::ProRata,
-        "fifo" | _ => MatchingMode::Fifo,
-    };
+    // Load tradable instruments from the config file
+    let config_path = env::args().nth(1).unwrap_or_else(|| "instruments.json".to_string());
+    let registry = InstrumentRegistry::load(&config_path)?;
 
-    println!("🔧 Matching Mode: {:?}", mode);
+    for instrument in registry.iter() {
+        println!("📚 {} ({:?}, {})", instrument.symbol, instrument.matching_mode, instrument.status.as_str());
+    }
 
-    // Initialize engine + redis
-    let book = Arc::new(Mutex::new(OrderBook::new(mode)));
-    let redis = RedisWriter::new("redis://127.0.0.1/");
+    // Initialize redis publisher + one engine task per symbol
+    let price_formats = registry.iter().map(|i| (i.symbol.clone(), i.price_format)).collect();
+    let events = RedisWriter::new("redis://127.0.0.1/", price_formats).spawn();
+    let books = BookManager::new(&registry, events);
 
     // Launch gRPC server
-    serve(book, redis).await
+    serve(books).await
 }
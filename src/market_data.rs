@@ -0,0 +1,99 @@
+use tokio::sync::broadcast;
+
+use crate::engine::{Depth, LevelUpdate, OrderBook, OrderEvent};
+use crate::models::Trade;
+
+/// How many updates a subscriber may fall behind by before it is dropped.
+const FEED_BUFFER: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarketDataEvent {
+    Level(LevelUpdate),
+    Trade(Trade),
+}
+
+/// One message of a feed. Sequence numbers start at 1 and have no gaps, so
+/// a subscriber can tell whether it missed anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequenced<E> {
+    pub sequence: u64,
+    pub event: E,
+}
+
+pub type MarketDataUpdate = Sequenced<MarketDataEvent>;
+
+/// What a new subscriber starts from: the book as of `sequence`, followed
+/// by every update after it. The snapshot is aggregated depth for the
+/// market data feed and `Add` events for the order feed.
+pub struct Subscription<S, E> {
+    pub sequence: u64,
+    pub snapshot: S,
+    pub updates: broadcast::Receiver<Sequenced<E>>,
+}
+
+pub type MarketDataSubscription = Subscription<Depth, MarketDataEvent>;
+pub type OrderFeedSubscription = Subscription<Vec<OrderEvent>, OrderEvent>;
+
+/// Numbers the events of one feed and fans them out to subscribers.
+struct Feed<E> {
+    tx: broadcast::Sender<Sequenced<E>>,
+    sequence: u64,
+}
+
+impl<E: Clone> Feed<E> {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(FEED_BUFFER);
+        Self { tx, sequence: 0 }
+    }
+
+    fn publish(&mut self, event: E) {
+        self.sequence += 1;
+        // Nobody listening is fine; late joiners start from a snapshot
+        let _ = self.tx.send(Sequenced { sequence: self.sequence, event });
+    }
+
+    fn subscribe<S>(&self, snapshot: S) -> Subscription<S, E> {
+        Subscription { sequence: self.sequence, snapshot, updates: self.tx.subscribe() }
+    }
+}
+
+/// One book's market data: the aggregated feed of level updates and trade
+/// prints, and the order-by-order feed. It is owned by the book task, so
+/// both are sequenced in matching order.
+pub struct MarketDataFeed {
+    levels: Feed<MarketDataEvent>,
+    orders: Feed<OrderEvent>,
+}
+
+impl Default for MarketDataFeed {
+    fn default() -> Self {
+        Self { levels: Feed::new(), orders: Feed::new() }
+    }
+}
+
+impl MarketDataFeed {
+    pub fn publish_trade(&mut self, trade: &Trade) {
+        self.levels.publish(MarketDataEvent::Trade(trade.clone()));
+    }
+
+    /// Publishes the book's pending level updates and order events.
+    pub fn publish_book(&mut self, book: &mut OrderBook) {
+        for update in book.take_level_updates() {
+            self.levels.publish(MarketDataEvent::Level(update));
+        }
+        for event in book.take_order_events() {
+            self.orders.publish(event);
+        }
+    }
+
+    /// Subscribes with a snapshot of `book`. Its pending changes must have
+    /// been published already, or the snapshot and the stream overlap.
+    pub fn subscribe(&self, book: &OrderBook) -> MarketDataSubscription {
+        self.levels.subscribe(book.depth(usize::MAX))
+    }
+
+    /// Subscribes to the order-by-order feed, with the same caveat.
+    pub fn subscribe_orders(&self, book: &OrderBook) -> OrderFeedSubscription {
+        self.orders.subscribe(book.order_snapshot())
+    }
+}
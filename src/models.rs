@@ -2,16 +2,66 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Side {
     Buy,
     Sell,
 }
 
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum OrderType {
     Market,
     Limit,
+    /// Becomes a market order once the last trade reaches `trigger_price`.
+    Stop,
+    /// Becomes a limit order at `price` once the last trade reaches
+    /// `trigger_price`.
+    StopLimit,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum TimeInForce {
+    /// Good till cancelled.
+    Gtc,
+    /// Immediate or cancel: fill what is possible, cancel the rest.
+    Ioc,
+    /// Fill or kill: fill completely on arrival or not at all.
+    Fok,
+    /// Rests until the end of the UTC trading day it was entered on.
+    Day,
+    /// Good till date: rests until the given time.
+    Gtd(DateTime<Utc>),
+}
+
+/// What a post-only order does if it would take liquidity on arrival.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum PostOnly {
+    Reject,
+    /// Reprice one tick behind the opposite best price and rest there.
+    Slide,
+}
+
+/// What happens when an incoming order would trade against a resting order
+/// from the same user or STP group.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SelfTradePrevention {
+    /// Cancel the rest of the incoming order.
+    CancelNewest,
+    /// Cancel the resting order and keep matching.
+    CancelOldest,
+    CancelBoth,
+    /// Reduce both orders by the smaller quantity without trading and cancel
+    /// whichever reaches zero.
+    DecrementAndCancel,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,8 +72,24 @@ pub struct Order {
     pub side: Side,
     pub order_type: OrderType,
     pub price: Option<u64>,
+    pub trigger_price: Option<u64>,
     pub quantity: u64,
+    /// For iceberg orders, the size of each slice shown in the book.
+    pub display_quantity: Option<u64>,
+    /// What is left of the slice currently shown; equals `quantity` for
+    /// ordinary orders.
+    pub shown_quantity: u64,
+    pub time_in_force: TimeInForce,
+    pub post_only: Option<PostOnly>,
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    /// Account or firm whose orders must not trade with each other; defaults
+    /// to the user id.
+    pub stp_group: Option<String>,
     pub timestamp: DateTime<Utc>,
+    /// Quantity executed so far; `quantity` is what is still open.
+    pub filled_quantity: u64,
+    /// Sum of price * quantity over all fills, for the average fill price.
+    pub filled_notional: u128,
 }
 
 impl Order {
@@ -35,17 +101,257 @@ impl Order {
             side,
             order_type,
             price,
+            trigger_price: None,
             quantity,
+            display_quantity: None,
+            shown_quantity: quantity,
+            time_in_force: TimeInForce::Gtc,
+            post_only: None,
+            self_trade_prevention: None,
+            stp_group: None,
             timestamp: Utc::now(),
+            filled_quantity: 0,
+            filled_notional: 0,
         }
     }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn with_post_only(mut self, post_only: PostOnly) -> Self {
+        self.post_only = Some(post_only);
+        self
+    }
+
+    pub fn with_self_trade_prevention(mut self, policy: SelfTradePrevention) -> Self {
+        self.self_trade_prevention = Some(policy);
+        self
+    }
+
+    /// Puts the order in an STP group. Resting orders carry their group even
+    /// without a policy of their own, so a policy on the incoming side still
+    /// recognizes them.
+    pub fn with_stp_group(mut self, stp_group: String) -> Self {
+        self.stp_group = Some(stp_group);
+        self
+    }
+
+    /// The key self-trade prevention compares: the STP group, or the user id.
+    pub fn stp_key(&self) -> &str {
+        self.stp_group.as_deref().unwrap_or(&self.user_id)
+    }
+
+    pub fn with_trigger_price(mut self, trigger_price: u64) -> Self {
+        self.trigger_price = Some(trigger_price);
+        self
+    }
+
+    /// Turns the order into an iceberg that shows at most `display_quantity`
+    /// at a time.
+    pub fn with_display_quantity(mut self, display_quantity: u64) -> Self {
+        self.display_quantity = Some(display_quantity);
+        self.refresh_display();
+        self
+    }
+
+    /// Shows the next slice: the display quantity, or everything that is
+    /// left if that is smaller.
+    pub fn refresh_display(&mut self) {
+        self.shown_quantity = match self.display_quantity {
+            Some(display) => display.min(self.quantity),
+            None => self.quantity,
+        };
+    }
+
+    /// True when an iceberg's shown slice is used up but reserve remains.
+    pub fn needs_replenish(&self) -> bool {
+        self.shown_quantity == 0 && self.quantity > 0
+    }
+
+    pub fn is_stop(&self) -> bool {
+        matches!(self.order_type, OrderType::Stop | OrderType::StopLimit)
+    }
+
+    /// When a resting order expires, if its time in force gives it a limit.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        match self.time_in_force {
+            TimeInForce::Day => {
+                let next_day = self.timestamp.date_naive().succ_opt()?;
+                Some(next_day.and_hms_opt(0, 0, 0)?.and_utc())
+            }
+            TimeInForce::Gtd(expiry) => Some(expiry),
+            _ => None,
+        }
+    }
+
+    /// Applies a fill. Fills are taken from the shown slice first; an order
+    /// that is not resting (an aggressor) shows everything, so its whole
+    /// quantity can trade.
+    pub fn record_fill(&mut self, price: u64, quantity: u64) {
+        self.quantity -= quantity;
+        self.shown_quantity = self.shown_quantity.saturating_sub(quantity);
+        self.filled_quantity += quantity;
+        self.filled_notional += price as u128 * quantity as u128;
+    }
+
+    pub fn average_fill_price(&self) -> f64 {
+        if self.filled_quantity == 0 {
+            return 0.0;
+        }
+        self.filled_notional as f64 / self.filled_quantity as f64
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Trade {
+    pub id: String,
+    /// Per-symbol trade sequence number, starting at 1.
+    pub sequence: u64,
+    pub symbol: String,
     pub price: u64,
     pub quantity: u64,
     pub buyer: String,
     pub seller: String,
+    pub buy_order_id: String,
+    pub sell_order_id: String,
+    /// Side of the incoming order that took liquidity.
+    pub aggressor_side: Side,
+    /// Part of `quantity` the resting order received as a lead market maker.
+    pub lmm_quantity: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ExecType {
+    New,
+    PartialFill,
+    Fill,
+    Cancelled,
+    Rejected,
+    Replaced,
+    Expired,
+    /// A stop order was activated by a trade at or through its trigger.
+    Triggered,
+}
+
+impl ExecType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecType::New => "new",
+            ExecType::PartialFill => "partial_fill",
+            ExecType::Fill => "fill",
+            ExecType::Cancelled => "cancelled",
+            ExecType::Rejected => "rejected",
+            ExecType::Replaced => "replaced",
+            ExecType::Expired => "expired",
+            ExecType::Triggered => "triggered",
+        }
+    }
+}
+
+/// Why a resting order received (or was denied) part of an incoming order.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AllocationStep {
+    /// Top-order priority for the oldest order at the level.
+    TopOrder,
+    /// Time priority: plain FIFO or the FIFO portion of a pro-rata split.
+    Fifo,
+    ProRata,
+    /// Lots left over after rounding the proportional split.
+    Leftover,
+    /// A proportional share below the minimum allocation, which was dropped.
+    BelowMinimum,
+    /// Largest shown quantity first.
+    SizePriority,
+    /// The lead market maker's guaranteed share.
+    LeadMarketMaker,
+}
+
+impl AllocationStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AllocationStep::TopOrder => "top_order",
+            AllocationStep::Fifo => "fifo",
+            AllocationStep::ProRata => "pro_rata",
+            AllocationStep::Leftover => "leftover",
+            AllocationStep::BelowMinimum => "below_minimum",
+            AllocationStep::SizePriority => "size_priority",
+            AllocationStep::LeadMarketMaker => "lead_market_maker",
+        }
+    }
+}
+
+/// Audit record of one allocation decision made while matching.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AllocationRecord {
+    pub symbol: String,
+    pub price: u64,
+    pub incoming_order_id: String,
+    pub resting_order_id: String,
+    pub resting_user_id: String,
+    pub step: AllocationStep,
+    pub quantity: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Describes one state transition of an order, as seen by its owner.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExecutionReport {
+    pub order_id: String,
+    pub user_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub exec_type: ExecType,
+    pub price: Option<u64>,
+    /// Price and quantity of the fill that produced this report, if any.
+    pub last_price: u64,
+    pub last_quantity: u64,
+    pub leaves_quantity: u64,
+    pub cumulative_quantity: u64,
+    pub average_price: f64,
+    pub reason: Option<String>,
     pub timestamp: DateTime<Utc>,
 }
+
+impl ExecutionReport {
+    pub fn new(order: &Order, exec_type: ExecType) -> Self {
+        // Orders that are done no longer have anything open
+        let leaves_quantity = match exec_type {
+            ExecType::Cancelled | ExecType::Rejected | ExecType::Expired => 0,
+            _ => order.quantity,
+        };
+
+        ExecutionReport {
+            order_id: order.id.clone(),
+            user_id: order.user_id.clone(),
+            symbol: order.symbol.clone(),
+            side: order.side,
+            exec_type,
+            price: order.price,
+            last_price: 0,
+            last_quantity: 0,
+            leaves_quantity,
+            cumulative_quantity: order.filled_quantity,
+            average_price: order.average_fill_price(),
+            reason: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Reports a fill that has already been applied to `order`.
+    pub fn fill(order: &Order, price: u64, quantity: u64) -> Self {
+        let exec_type = if order.quantity == 0 { ExecType::Fill } else { ExecType::PartialFill };
+        ExecutionReport {
+            last_price: price,
+            last_quantity: quantity,
+            ..Self::new(order, exec_type)
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
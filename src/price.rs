@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+/// How an instrument's decimal prices map onto the integers the engine
+/// matches on.
+///
+/// Inside the engine a price is a `u64` count of `10^-scale` units, so with
+/// `scale = 2` the price `150.25` is stored as `15025`. Decimal strings are
+/// only used at the gRPC and Redis boundaries, which keeps matching exact and
+/// lets every price round-trip losslessly.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriceFormat {
+    /// Number of decimal places.
+    pub scale: u32,
+    /// Smallest price increment, in scaled units.
+    pub tick_size: u64,
+}
+
+impl Default for PriceFormat {
+    /// Two decimal places traded in whole cents.
+    fn default() -> Self {
+        Self { scale: 2, tick_size: 1 }
+    }
+}
+
+impl PriceFormat {
+    pub fn new(scale: u32, tick_size: u64) -> Self {
+        Self { scale, tick_size }
+    }
+
+    /// Parses a decimal string such as `"150.5"` into scaled units. Digits
+    /// beyond the scale are rejected rather than rounded, unless they are
+    /// zeros.
+    pub fn parse(&self, text: &str) -> Result<u64, &'static str> {
+        let text = text.trim();
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err("price is empty");
+        }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err("price must be a non-negative decimal number");
+        }
+
+        let significant = fraction.trim_end_matches('0');
+        if significant.len() > self.scale as usize {
+            return Err("price has more decimal places than the instrument allows");
+        }
+
+        let digits = format!("{}{:0<width$}", whole, significant, width = self.scale as usize);
+        digits.parse::<u64>().map_err(|_| "price is too large")
+    }
+
+    /// Formats scaled units as a decimal string with exactly `scale` decimal
+    /// places, e.g. `15050` at scale 2 is `"150.50"`.
+    pub fn format(&self, price: u64) -> String {
+        if self.scale == 0 {
+            return price.to_string();
+        }
+        let unit = 10u64.pow(self.scale);
+        format!("{}.{:0width$}", price / unit, price % unit, width = self.scale as usize)
+    }
+
+    /// Converts a value in scaled units, such as an average fill price, to
+    /// a plain float for consumers that do arithmetic like PnL.
+    pub fn unscale(&self, price: f64) -> f64 {
+        price / 10f64.powi(self.scale as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scales_decimal_prices() {
+        let format = PriceFormat::new(2, 1);
+        assert_eq!(format.parse("150.5"), Ok(15050));
+        assert_eq!(format.parse("150.25"), Ok(15025));
+        assert_eq!(format.parse("150"), Ok(15000));
+        assert_eq!(format.parse("0.01"), Ok(1));
+        assert_eq!(format.parse(".5"), Ok(50));
+        assert_eq!(format.parse("150.2500"), Ok(15025));
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_prices() {
+        let format = PriceFormat::new(2, 1);
+        assert!(format.parse("150.255").is_err());
+        assert!(format.parse("-1").is_err());
+        assert!(format.parse("abc").is_err());
+        assert!(format.parse("").is_err());
+        assert!(format.parse("1.2.3").is_err());
+        assert!(format.parse("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_prices_round_trip() {
+        for format in [PriceFormat::new(0, 1), PriceFormat::new(2, 5), PriceFormat::new(8, 1)] {
+            for price in [0, 1, 5, 15050, 123_456_789, u64::MAX] {
+                assert_eq!(format.parse(&format.format(price)), Ok(price));
+            }
+        }
+        assert_eq!(PriceFormat::new(2, 1).format(15050), "150.50");
+        assert_eq!(PriceFormat::new(4, 1).format(7), "0.0007");
+        assert_eq!(PriceFormat::new(0, 1).format(150), "150");
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use crate::models::Order;
+
+/// Orders resting at a single price, in time priority.
+///
+/// Each order sits in a slot keyed by its arrival sequence number, so an
+/// order can be removed from the middle of the queue without shifting the
+/// orders behind it.
+#[derive(Debug, Default)]
+pub struct PriceLevel {
+    orders: BTreeMap<u64, Order>,
+}
+
+impl PriceLevel {
+    pub fn push_back(&mut self, slot: u64, order: Order) {
+        self.orders.insert(slot, order);
+    }
+
+    #[cfg(test)]
+    pub fn front(&self) -> Option<&Order> {
+        self.orders.values().next()
+    }
+
+    #[cfg(test)]
+    pub fn back(&self) -> Option<&Order> {
+        self.orders.values().next_back()
+    }
+
+    pub fn get(&self, slot: u64) -> Option<&Order> {
+        self.orders.get(&slot)
+    }
+
+    pub fn get_mut(&mut self, slot: u64) -> Option<&mut Order> {
+        self.orders.get_mut(&slot)
+    }
+
+    pub fn remove(&mut self, slot: u64) -> Option<Order> {
+        self.orders.remove(&slot)
+    }
+
+    /// Iterates over `(slot, order)` pairs in time priority.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Order)> {
+        self.orders.iter().map(|(slot, order)| (*slot, order))
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// Total open quantity, including the hidden reserve of iceberg orders.
+    /// Saturates at `u64::MAX`, which instruments allow as an order size.
+    pub fn total_quantity(&self) -> u64 {
+        self.orders.values().fold(0, |sum, o| sum.saturating_add(o.quantity))
+    }
+
+    /// Quantity shown in the book: iceberg orders count only their slice.
+    /// Saturates like `total_quantity`.
+    pub fn visible_quantity(&self) -> u64 {
+        self.orders.values().fold(0, |sum, o| sum.saturating_add(o.shown_quantity))
+    }
+}
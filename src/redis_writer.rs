@@ -1,67 +1,312 @@
-use redis::AsyncCommands;
-use crate::models::Trade;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::aio::Connection;
+use redis::Pipeline;
+use tokio::sync::mpsc;
+use crate::engine::Quote;
+use crate::models::{AllocationRecord, ExecutionReport, Order, Trade};
+use crate::price::PriceFormat;
+
+/// Something the matching engine wants broadcast through Redis.
+#[derive(Debug, Clone)]
+pub enum RedisEvent {
+    Trade(Trade),
+    Cancel(Order),
+    ExecutionReport(ExecutionReport),
+    Allocation(AllocationRecord),
+    /// A symbol's best bid/offer or last trade changed.
+    Quote(String, Quote),
+}
+
+/// How many events may wait for Redis before the books wait for it.
+pub const EVENT_QUEUE_DEPTH: usize = 65_536;
+
+/// Most events written in one pipelined round trip.
+const WRITE_BATCH: usize = 512;
+
+/// Longest wait between attempts to write a batch Redis did not take.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The order books' end of the writer's queue.
+///
+/// The queue is bounded, so a slow or unreachable Redis cannot grow memory
+/// without limit. When it is full, quotes are dropped and counted, since
+/// the next quote change replaces them. Trades, cancels, execution reports
+/// and allocation records wait for room instead, holding up the book that
+/// sent them.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<RedisEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    pub async fn send(&self, event: RedisEvent) {
+        match event {
+            RedisEvent::Quote(..) => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(event) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            // Only fails once the writer is gone, and then there is nowhere
+            // left to send it
+            event => {
+                let _ = self.tx.send(event).await;
+            }
+        }
+    }
+
+    /// Quotes dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Creates a queue of `capacity` events.
+pub fn event_queue(capacity: usize) -> (EventSender, mpsc::Receiver<RedisEvent>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (EventSender { tx, dropped: Arc::new(AtomicU64::new(0)) }, rx)
+}
 
 pub struct RedisWriter {
     pub client: redis::Client,
+    conn: Option<Connection>,
+    /// Per-symbol price formats; prices are written as decimal strings.
+    price_formats: HashMap<String, PriceFormat>,
 }
 
 impl RedisWriter {
-    pub fn new(redis_url: &str) -> Self {
+    pub fn new(redis_url: &str, price_formats: HashMap<String, PriceFormat>) -> Self {
         let client = redis::Client::open(redis_url).expect("Failed to connect to Redis");
-        Self { client }
+        Self { client, conn: None, price_formats }
+    }
+
+    fn price_format(&self, symbol: &str) -> PriceFormat {
+        self.price_formats.get(symbol).copied().unwrap_or_default()
+    }
+
+    /// Moves the writer onto a background task and returns the queue the
+    /// order books push events into. Events are published in the order they
+    /// are received, so matching only waits on Redis I/O once the queue is
+    /// full. Whatever has queued up is written in pipelined batches.
+    ///
+    /// A batch that does not reach Redis is retried until it does. Dropped
+    /// quotes are logged once the writer is caught up.
+    pub fn spawn(mut self) -> EventSender {
+        let (tx, mut rx) = event_queue(EVENT_QUEUE_DEPTH);
+        let dropped = tx.dropped.clone();
+        tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(WRITE_BATCH);
+            let mut reported = 0;
+            while rx.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+                let mut delay = Duration::from_millis(100);
+                while !self.write(&batch).await {
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                batch.clear();
+
+                let dropped = dropped.load(Ordering::Relaxed);
+                if dropped > reported && rx.is_empty() {
+                    eprintln!("Redis queue was full: {} quotes dropped so far", dropped);
+                    reported = dropped;
+                }
+            }
+        });
+        tx
     }
 
-    pub async fn publish_trade(&self, trade: &Trade) {
-        let mut conn = match self.client.get_async_connection().await {
-            Ok(conn) => conn,
-            Err(err) => {
-                eprintln!("Redis connection error: {:?}", err);
-                return;
+    /// Writes a batch of events in one round trip, in order, as a MULTI/EXEC
+    /// transaction so a retried batch was not already half written. Returns
+    /// false if the batch should be retried: Redis could not be reached or
+    /// the connection failed. A batch Redis refuses is logged and dropped.
+    async fn write(&mut self, events: &[RedisEvent]) -> bool {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for event in events {
+            match event {
+                RedisEvent::Trade(trade) => {
+                    self.publish_trade(&mut pipe, trade);
+                    self.update_user_pnl(&mut pipe, trade);
+                }
+                RedisEvent::Cancel(order) => self.publish_cancel(&mut pipe, order),
+                RedisEvent::ExecutionReport(report) => self.publish_execution_report(&mut pipe, report),
+                RedisEvent::Allocation(record) => self.publish_allocation(&mut pipe, record),
+                RedisEvent::Quote(symbol, quote) => self.publish_quote(&mut pipe, symbol, quote),
             }
+        }
+
+        let conn = match self.connection().await {
+            Some(conn) => conn,
+            None => return false,
         };
+        match pipe.query_async::<_, ()>(conn).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Failed to write to Redis: {:?}", e);
+                // Reconnect next time instead of reusing a broken connection
+                self.conn = None;
+                !(e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout())
+            }
+        }
+    }
+
+    /// Returns the cached connection, reconnecting if the last one failed.
+    async fn connection(&mut self) -> Option<&mut Connection> {
+        if self.conn.is_none() {
+            match self.client.get_async_connection().await {
+                Ok(conn) => self.conn = Some(conn),
+                Err(err) => {
+                    eprintln!("Redis connection error: {:?}", err);
+                    return None;
+                }
+            }
+        }
+        self.conn.as_mut()
+    }
 
-        let _: () = conn.xadd(
+    fn publish_trade(&self, pipe: &mut Pipeline, trade: &Trade) {
+        let format = self.price_format(&trade.symbol);
+        pipe.xadd(
             "trades_stream",
             "*",
             &[
-                ("price", trade.price.to_string()),
+                ("trade_id", trade.id.clone()),
+                ("sequence", trade.sequence.to_string()),
+                ("symbol", trade.symbol.clone()),
+                ("price", format.format(trade.price)),
                 ("quantity", trade.quantity.to_string()),
                 ("buyer", trade.buyer.clone()),
                 ("seller", trade.seller.clone()),
+                ("buy_order_id", trade.buy_order_id.clone()),
+                ("sell_order_id", trade.sell_order_id.clone()),
+                ("aggressor_side", trade.aggressor_side.as_str().to_string()),
+                ("lmm_quantity", trade.lmm_quantity.to_string()),
                 ("timestamp", trade.timestamp.to_rfc3339()),
             ],
-        ).await.unwrap_or_else(|e| {
-            eprintln!("Failed to push to Redis: {:?}", e);
+        ).ignore();
+    }
+
+    fn publish_cancel(&self, pipe: &mut Pipeline, order: &Order) {
+        let format = self.price_format(&order.symbol);
+        pipe.xadd(
+            "cancels_stream",
+            "*",
+            &[
+                ("order_id", order.id.clone()),
+                ("user_id", order.user_id.clone()),
+                ("symbol", order.symbol.clone()),
+                ("side", order.side.as_str().to_string()),
+                ("price", format.format(order.price.unwrap_or_default())),
+                ("remaining_quantity", order.quantity.to_string()),
+                ("timestamp", chrono::Utc::now().to_rfc3339()),
+            ],
+        ).ignore();
+    }
+
+    /// Appends a report to its owner's `exec_reports:<user_id>` stream.
+    fn publish_execution_report(&self, pipe: &mut Pipeline, report: &ExecutionReport) {
+        let format = self.price_format(&report.symbol);
+        pipe.xadd(
+            format!("exec_reports:{}", report.user_id),
+            "*",
+            &[
+                ("order_id", report.order_id.clone()),
+                ("symbol", report.symbol.clone()),
+                ("side", report.side.as_str().to_string()),
+                ("exec_type", report.exec_type.as_str().to_string()),
+                ("price", format.format(report.price.unwrap_or_default())),
+                ("last_price", format.format(report.last_price)),
+                ("last_quantity", report.last_quantity.to_string()),
+                ("leaves_quantity", report.leaves_quantity.to_string()),
+                ("cumulative_quantity", report.cumulative_quantity.to_string()),
+                ("average_price", format.unscale(report.average_price).to_string()),
+                ("reason", report.reason.clone().unwrap_or_default()),
+                ("timestamp", report.timestamp.to_rfc3339()),
+            ],
+        ).ignore();
+    }
+
+    /// Appends an allocation decision to the `allocations_stream` audit log.
+    fn publish_allocation(&self, pipe: &mut Pipeline, record: &AllocationRecord) {
+        let format = self.price_format(&record.symbol);
+        pipe.xadd(
+            "allocations_stream",
+            "*",
+            &[
+                ("symbol", record.symbol.clone()),
+                ("price", format.format(record.price)),
+                ("incoming_order_id", record.incoming_order_id.clone()),
+                ("resting_order_id", record.resting_order_id.clone()),
+                ("resting_user_id", record.resting_user_id.clone()),
+                ("step", record.step.as_str().to_string()),
+                ("quantity", record.quantity.to_string()),
+                ("timestamp", record.timestamp.to_rfc3339()),
+            ],
+        ).ignore();
+    }
+
+    /// Publishes the quote as JSON on the symbol's `quotes:{symbol}` pub/sub
+    /// channel. Missing prices and quantities are `null`.
+    fn publish_quote(&self, pipe: &mut Pipeline, symbol: &str, quote: &Quote) {
+        let format = self.price_format(symbol);
+        let message = serde_json::json!({
+            "symbol": symbol,
+            "bid_price": quote.bid.as_ref().map(|l| format.format(l.price)),
+            "bid_quantity": quote.bid.as_ref().map(|l| l.quantity),
+            "ask_price": quote.ask.as_ref().map(|l| format.format(l.price)),
+            "ask_quantity": quote.ask.as_ref().map(|l| l.quantity),
+            "spread": quote.spread().map(|spread| format.format(spread)),
+            "last_trade_price": quote.last_trade_price.map(|price| format.format(price)),
+            "last_trade_quantity": quote.last_trade_quantity,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
         });
+        pipe.publish(format!("quotes:{}", symbol), message.to_string()).ignore();
     }
 
-    pub async fn update_user_pnl(&self, trade: &Trade) {
-        let mut conn = match self.client.get_async_connection().await {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Redis error (PNL): {:?}", e);
-                return;
-            }
-        };
-    
-        let buyer_key = format!("user_pnl:{}", trade.buyer);
-        let seller_key = format!("user_pnl:{}", trade.seller);
-        let value = (trade.price * trade.quantity) as f64;
-    
-        // Use raw redis::cmd to call HINCRBYFLOAT
-        let _ = redis::cmd("HINCRBYFLOAT")
-            .arg(&buyer_key)
-            .arg("pnl")
-            .arg(-1.0 * value)
-            .query_async::<_, ()>(&mut conn)
-            .await;
-    
-        let _ = redis::cmd("HINCRBYFLOAT")
-            .arg(&seller_key)
-            .arg("pnl")
-            .arg(value)
-            .query_async::<_, ()>(&mut conn)
-            .await;
-    }
-    
+    fn update_user_pnl(&self, pipe: &mut Pipeline, trade: &Trade) {
+        let value = self.price_format(&trade.symbol).unscale(trade.price as f64) * trade.quantity as f64;
+        let buyer_key = format!("user_pnl:{}:{}", trade.buyer, trade.symbol);
+        let seller_key = format!("user_pnl:{}:{}", trade.seller, trade.symbol);
+
+        pipe.cmd("HINCRBYFLOAT").arg(&buyer_key).arg("pnl").arg(-value).ignore()
+            .cmd("HINCRBYFLOAT").arg(&seller_key).arg("pnl").arg(value).ignore();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{OrderType, Side};
+
+    fn cancel() -> RedisEvent {
+        RedisEvent::Cancel(Order::new("u1".to_string(), "AAPL".to_string(), Side::Buy, OrderType::Limit, Some(100), 1))
+    }
+
+    fn quote() -> RedisEvent {
+        RedisEvent::Quote("AAPL".to_string(), Quote::default())
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_quotes_and_holds_back_the_rest() {
+        let (events, mut rx) = event_queue(2);
+        for _ in 0..5 {
+            events.send(quote()).await;
+        }
+        assert_eq!(events.dropped(), 3);
+
+        // A cancel waits until the writer makes room
+        let sender = events.clone();
+        let waiting = tokio::spawn(async move { sender.send(cancel()).await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert!(matches!(rx.recv().await, Some(RedisEvent::Quote(..))));
+        waiting.await.unwrap();
+        assert!(matches!(rx.recv().await, Some(RedisEvent::Quote(..))));
+        assert!(matches!(rx.recv().await, Some(RedisEvent::Cancel(_))));
+        assert_eq!(events.dropped(), 3);
+    }
 }
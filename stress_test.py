@@ -2,37 +2,64 @@ import grpc
 import order_pb2
 import order_pb2_grpc
 import random
+import sys
 import time
 import uuid
+from concurrent.futures import ThreadPoolExecutor
 
 TOTAL_ORDERS = 1000
+CONCURRENCY = 16
+SYMBOLS = ["AAPL"]  # must be listed in the engine's instruments config (see instruments.json)
 
-def random_order():
+def random_order(user_id=None):
     return order_pb2.OrderRequest(
-        user_id=str(uuid.uuid4())[:8],
-        symbol="AAPL",
+        user_id=user_id or str(uuid.uuid4())[:8],
+        symbol=random.choice(SYMBOLS),
         side=random.choice(["buy", "sell"]),
         order_type="limit",
         quantity=random.randint(1, 10),
-        price=random.randint(145, 155)
+        price=f"{random.randint(14500, 15500) / 100:.2f}"
     )
 
-def run():
+def submit(stub, i):
+    try:
+        response = stub.SubmitOrder(random_order())
+        trades = len(response.trades)
+    except grpc.RpcError as e:
+        print(f"❌ gRPC error: {e.code()}")
+        trades = 0
+    if i % 100 == 0:
+        print(f"Submitted {i} orders...")
+    return trades
+
+def run_unary(stub):
+    with ThreadPoolExecutor(max_workers=CONCURRENCY) as pool:
+        return sum(pool.map(lambda i: submit(stub, i), range(TOTAL_ORDERS)))
+
+def run_session(stub):
+    # Every order goes out on one stream without waiting for its reply. A
+    # session belongs to one user, so all of its orders share a user_id.
+    user_id = str(uuid.uuid4())[:8]
+    requests = (order_pb2.SessionRequest(request_id=str(i), submit=random_order(user_id)) for i in range(TOTAL_ORDERS))
+    trades = 0
+    for message in stub.OrderSession(requests):
+        if message.HasField("report"):
+            continue
+        if message.HasField("rejected"):
+            print(f"❌ Rejected {message.request_id}: {message.rejected.message}")
+        else:
+            trades += len(message.submitted.trades)
+        if int(message.request_id) % 100 == 0:
+            print(f"Submitted {message.request_id} orders...")
+    return trades
+
+def run(session):
     channel = grpc.insecure_channel("localhost:50051")
     stub = order_pb2_grpc.OrderMatchingStub(channel)
 
-    total_trades = 0
     start = time.time()
 
-    for i in range(TOTAL_ORDERS):
-        req = random_order()
-        try:
-            response = stub.SubmitOrder(req)
-            total_trades += len(response.trades)
-        except grpc.RpcError as e:
-            print(f"❌ gRPC error: {e.code()}")
-        if i % 100 == 0:
-            print(f"Submitted {i} orders...")
+    total_trades = run_session(stub) if session else run_unary(stub)
 
     duration = time.time() - start
     print("\n✅ Stress Test Complete")
@@ -43,4 +70,4 @@ def run():
     print(f"Avg Trades/order: {total_trades / TOTAL_ORDERS:.2f}")
 
 if __name__ == "__main__":
-    run()
+    run("--session" in sys.argv)
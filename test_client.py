@@ -12,7 +12,7 @@ def run():
         side="buy",
         order_type="limit",
         quantity=5,
-        price=151
+        price="151.00"
     )
 
     response = stub.SubmitOrder(request)